@@ -0,0 +1,252 @@
+use std::process::ExitCode;
+
+use card_emu_host::{AddrRange, Bridge, Result, Transport};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
+use serde_json::json;
+
+// registers per line of a dump
+const DUMP_ROW_LEN: usize = 16;
+
+// 0x00 to 0xFF in order
+const EVERY_ADDR: AddrRange = AddrRange {
+    start: 0,
+    stride: 1,
+    wrap: 256,
+};
+
+#[derive(Parser)]
+#[command(
+    name = "card-emu",
+    version,
+    about = "Manual access to a card through the bridge"
+)]
+struct Args {
+    #[arg(
+        long,
+        global = true,
+        help = "Serial number of the bridge to use, if more than one is connected"
+    )]
+    device: Option<String>,
+
+    #[arg(long, global = true, help = "Print results as JSON")]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+// addresses and values are decimal, or hex with a 0x prefix
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "List the serial numbers of connected bridges")]
+    List,
+
+    #[command(about = "Read a register")]
+    Peek {
+        #[arg(value_parser = parse_u8)]
+        addr: u8,
+    },
+
+    #[command(about = "Write a register")]
+    Poke {
+        #[arg(value_parser = parse_u8)]
+        addr: u8,
+        #[arg(value_parser = parse_u8)]
+        data: u8,
+    },
+
+    #[command(about = "Read every register")]
+    Dump,
+
+    #[command(about = "Write the same value to every register from first to last inclusive")]
+    Fill {
+        #[arg(value_parser = parse_u8)]
+        first: u8,
+        #[arg(value_parser = parse_u8)]
+        last: u8,
+        #[arg(value_parser = parse_u8)]
+        data: u8,
+    },
+
+    #[command(about = "Reboot the bridge into the USB bootloader, ready for flashing")]
+    Reboot,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    if let Command::Fill { first, last, .. } = args.command
+        && first > last
+    {
+        Args::command()
+            .error(
+                ErrorKind::ValueValidation,
+                format!("the range {first:#04x}..={last:#04x} is empty"),
+            )
+            .exit();
+    }
+
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &Args) -> Result<()> {
+    if let Command::List = args.command {
+        let serials = Bridge::list()?;
+
+        if args.json {
+            println!("{}", json!(serials));
+        } else {
+            for serial in serials {
+                println!("{serial}");
+            }
+        }
+
+        return Ok(());
+    }
+
+    let bridge = Bridge::open(args.device.as_deref())?;
+
+    execute(bridge, &args.command, args.json)
+}
+
+fn execute<T: Transport>(bridge: Bridge<T>, command: &Command, json: bool) -> Result<()> {
+    match *command {
+        Command::List => unreachable!(),
+
+        Command::Peek { addr } => {
+            let value = bridge.read(addr)?;
+
+            if json {
+                println!("{}", json!({ "addr": addr, "value": value }));
+            } else {
+                println!("{value:#04x}");
+            }
+        }
+
+        Command::Poke { addr, data } => {
+            bridge.write(addr, data)?;
+
+            if json {
+                println!("{}", json!({ "addr": addr, "data": data }));
+            }
+        }
+
+        Command::Dump => {
+            let data = bridge.read_range(EVERY_ADDR, 256)?;
+
+            if json {
+                println!("{}", json!({ "data": data }));
+            } else {
+                for (row, values) in data.chunks(DUMP_ROW_LEN).enumerate() {
+                    let values = values
+                        .iter()
+                        .map(|b| format!("{b:02x}"))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    println!("{:02x}: {values}", row * DUMP_ROW_LEN);
+                }
+            }
+        }
+
+        Command::Fill { first, last, data } => {
+            let range = AddrRange {
+                start: first,
+                ..EVERY_ADDR
+            };
+            let len = usize::from(last - first) + 1;
+            bridge.write_range(range, &vec![data; len])?;
+
+            if json {
+                println!("{}", json!({ "first": first, "last": last, "data": data }));
+            }
+        }
+
+        Command::Reboot => {
+            let serial = bridge.serial().to_owned();
+            bridge.reboot_to_bootsel()?;
+
+            if json {
+                println!("{}", json!({ "serial": serial }));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// hex with a 0x prefix, otherwise decimal
+fn parse_u8(s: &str) -> std::result::Result<u8, String> {
+    let res = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+
+    res.map_err(|e| format!("{s:?} is not a byte: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use card_emu_host::VirtualBridge;
+
+    use super::*;
+
+    #[test]
+    fn parses_hex_and_decimal() {
+        assert_eq!(parse_u8("0x1f"), Ok(0x1F));
+        assert_eq!(parse_u8("0XFF"), Ok(0xFF));
+        assert_eq!(parse_u8("31"), Ok(31));
+        assert_eq!(parse_u8("0"), Ok(0));
+    }
+
+    #[test]
+    fn rejects_bad_bytes() {
+        assert!(parse_u8("256").is_err());
+        assert!(parse_u8("0x100").is_err());
+        assert!(parse_u8("0x").is_err());
+        assert!(parse_u8("1f").is_err());
+        assert!(parse_u8("-1").is_err());
+    }
+
+    #[test]
+    fn commands_run_against_a_virtual_bridge() {
+        let model = VirtualBridge::new();
+        let bridge = || Bridge::new(&model, "VIRTUAL");
+
+        let fill = Command::Fill {
+            first: 0x10,
+            last: 0x1F,
+            data: 0xAA,
+        };
+        execute(bridge(), &fill, false).unwrap();
+        execute(
+            bridge(),
+            &Command::Poke {
+                addr: 0x20,
+                data: 1,
+            },
+            false,
+        )
+        .unwrap();
+        execute(bridge(), &Command::Dump, true).unwrap();
+
+        let registers = model.bus().registers;
+        assert!(registers[0x10..=0x1F].iter().all(|&b| b == 0xAA));
+        assert_eq!(registers[0x0F], 0);
+        assert_eq!(registers[0x20], 1);
+
+        execute(bridge(), &Command::Reboot, false).unwrap();
+        assert!(model.rebooted());
+    }
+
+    #[test]
+    fn args_are_consistent() {
+        Args::command().debug_assert();
+    }
+}
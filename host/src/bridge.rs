@@ -0,0 +1,324 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use card_emu_protocol::Request;
+use card_emu_protocol::handler::VM_TIMEOUT_US;
+use card_emu_protocol::range::AddrRange;
+use card_emu_protocol::request::WAIT_FOR_UNIT_US;
+use card_emu_protocol::response::{BUFFER_LEN_LEN, LAST_ERROR_LEN, LastError, decode_buffer_len};
+use card_emu_protocol::vm::MAX_DELAY_US;
+use rusb::{Context, DeviceHandle, UsbContext};
+
+use crate::error::{BridgeError, Error, Result};
+use crate::transport::Transport;
+
+pub const VID: u16 = 0x0ED2;
+pub const PID: u16 = 0x64DD;
+
+const INTERFACE: u8 = 0;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+// between checks on the bridge's receive buffer, so as not to flood the bus with requests
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+// a WaitFor that runs to its timeout has to answer before the control transfer gives up on it
+const _: () = assert!(u8::MAX as u64 * WAIT_FOR_UNIT_US * 2 <= DEFAULT_TIMEOUT.as_micros() as u64);
+
+// as does a RunProgram or RunScript that runs to the bridge's limit, with a Delay to finish
+const _: () = assert!((VM_TIMEOUT_US + MAX_DELAY_US) * 2 <= DEFAULT_TIMEOUT.as_micros() as u64);
+
+// well inside the bridge's receive buffer, and small enough for wIndex
+const CHUNK_LEN: usize = 4096;
+
+pub struct Bridge<T = DeviceHandle<Context>> {
+    handle: T,
+    serial: String,
+    timeout: Duration,
+}
+
+impl Bridge {
+    // serial numbers of every connected bridge
+    pub fn list() -> Result<Vec<String>> {
+        Ok(Self::find()?
+            .into_iter()
+            .map(|(_, serial)| serial)
+            .collect())
+    }
+
+    // with no serial number, there must be exactly one bridge connected
+    pub fn open(serial: Option<&str>) -> Result<Self> {
+        let mut found = Self::find()?;
+
+        if let Some(serial) = serial {
+            found.retain(|(_, s)| s == serial);
+        }
+
+        let (handle, serial) = match found.len() {
+            0 => return Err(Error::NotFound),
+            1 => found.remove(0),
+            n => return Err(Error::Ambiguous(n)),
+        };
+
+        handle.claim_interface(INTERFACE)?;
+
+        Ok(Self {
+            handle,
+            serial,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    fn find() -> Result<Vec<(DeviceHandle<Context>, String)>> {
+        let mut found = Vec::new();
+
+        // rusb's global context panics if libusb can't start, where this fails cleanly
+        let context = Context::new()?;
+
+        for device in context.devices()?.iter() {
+            let Ok(desc) = device.device_descriptor() else {
+                continue;
+            };
+
+            if desc.vendor_id() != VID || desc.product_id() != PID {
+                continue;
+            }
+
+            // one that's held by another process, or that has gone away since, shouldn't stop
+            // the rest from being found
+            let Ok(handle) = device.open() else {
+                continue;
+            };
+            let Ok(serial) = handle.read_serial_number_string_ascii(&desc) else {
+                continue;
+            };
+
+            found.push((handle, serial));
+        }
+
+        Ok(found)
+    }
+}
+
+impl<T: Transport> Bridge<T> {
+    // over anything that isn't a bridge found over USB, such as a VirtualBridge
+    pub fn new(handle: T, serial: &str) -> Self {
+        Self {
+            handle,
+            serial: serial.to_owned(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn serial(&self) -> &str {
+        &self.serial
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn read(&self, addr: u8) -> Result<u8> {
+        let mut data = [0; 1];
+
+        match self.control_in(Request::Read { addr }, &mut data)? {
+            1 => Ok(data[0]),
+            _ => Err(Error::BadResponse(BridgeError::BadLength)),
+        }
+    }
+
+    pub fn write(&self, addr: u8, data: u8) -> Result<()> {
+        self.control_out(Request::Write { addr, data })
+    }
+
+    // writes each byte of `data` to `addr` in turn
+    pub fn write_from_buf(&self, addr: u8, data: &[u8]) -> Result<()> {
+        self.queue_writes(data, CHUNK_LEN, |len| Request::WriteFromBuf { addr, len })
+    }
+
+    // writes each byte of `data` to `addr` as eight writes of one bit each, LSB first, in bit 0
+    pub fn write_bits_from_buf(&self, addr: u8, data: &[u8]) -> Result<()> {
+        self.queue_writes(data, CHUNK_LEN, |len| Request::WriteBitsFromBuf {
+            addr,
+            len,
+        })
+    }
+
+    // writes the nth byte of `data` to the nth address of `range`
+    pub fn write_range(&self, range: AddrRange, data: &[u8]) -> Result<()> {
+        self.queue_writes(data, range_chunk_len(range), |len| {
+            Request::WriteRangeFromBuf { range, len }
+        })
+    }
+
+    // reads `addr` `len` times, collecting the results over bulk IN
+    pub fn read_into_buf(&self, addr: u8, len: usize) -> Result<Vec<u8>> {
+        self.queue_reads(len, CHUNK_LEN, |len| Request::ReadIntoBuf { addr, len })
+    }
+
+    // reads the first `len` addresses of `range`, as read_into_buf
+    pub fn read_range(&self, range: AddrRange, len: usize) -> Result<Vec<u8>> {
+        self.queue_reads(len, range_chunk_len(range), |len| {
+            Request::ReadRangeIntoBuf { range, len }
+        })
+    }
+
+    // bytes sent over bulk OUT that the bridge hasn't consumed yet
+    pub fn recv_len(&self) -> Result<u32> {
+        self.buffer_len(Request::GetRecvLen)
+    }
+
+    // bytes queued on bulk IN that the host hasn't collected yet
+    pub fn send_len(&self) -> Result<u32> {
+        self.buffer_len(Request::GetSendLen)
+    }
+
+    // the bridge resets into the RP2350's USB bootloader, so this never sees the status stage
+    pub fn reboot_to_bootsel(self) -> Result<()> {
+        let request = Request::RebootToUSB.encode();
+
+        match self
+            .handle
+            .write_control(&request.setup, request.data(), self.timeout)
+        {
+            Ok(_)
+            | Err(rusb::Error::NoDevice)
+            | Err(rusb::Error::Io)
+            | Err(rusb::Error::Pipe)
+            | Err(rusb::Error::Timeout) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn queue_writes(
+        &self,
+        data: &[u8],
+        chunk_len: usize,
+        request: impl Fn(u16) -> Request<'static>,
+    ) -> Result<()> {
+        for chunk in data.chunks(chunk_len) {
+            self.send(chunk)?;
+            self.wait_recv(chunk.len())?;
+            self.control_out(request(chunk.len() as u16))?;
+        }
+
+        Ok(())
+    }
+
+    // each chunk is collected before the next is asked for, so the send buffer never fills
+    fn queue_reads(
+        &self,
+        len: usize,
+        chunk_len: usize,
+        request: impl Fn(u16) -> Request<'static>,
+    ) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len);
+
+        while data.len() < len {
+            let n = (len - data.len()).min(chunk_len);
+            self.control_out(request(n as u16))?;
+            self.collect(&mut data, n)?;
+        }
+
+        Ok(data)
+    }
+
+    fn buffer_len(&self, request: Request) -> Result<u32> {
+        let mut data = [0; BUFFER_LEN_LEN];
+        let n = self.control_in(request, &mut data)?;
+        decode_buffer_len(&data[..n]).map_err(Error::BadResponse)
+    }
+
+    fn send(&self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            let n = self.handle.write_bulk(data, self.timeout)?;
+            data = &data[n..];
+        }
+
+        Ok(())
+    }
+
+    // appends the next `len` bytes from bulk IN to `data`
+    fn collect(&self, data: &mut Vec<u8>, len: usize) -> Result<()> {
+        let end = data.len() + len;
+        let mut buf = [0; CHUNK_LEN];
+
+        while data.len() < end {
+            let want = (end - data.len()).min(buf.len());
+            let n = self.handle.read_bulk(&mut buf[..want], self.timeout)?;
+            data.extend_from_slice(&buf[..n]);
+        }
+
+        Ok(())
+    }
+
+    // the last packet may have been acknowledged before the bridge has taken it from the
+    // endpoint, and a request sent before then would be rejected with BadLength
+    fn wait_recv(&self, len: usize) -> Result<()> {
+        let deadline = Instant::now() + self.timeout;
+
+        while (self.recv_len()? as usize) < len {
+            if Instant::now() >= deadline {
+                return Err(Error::RecvTimeout(len));
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        Ok(())
+    }
+
+    // the number of bytes returned
+    fn control_in(&self, request: Request, buf: &mut [u8]) -> Result<usize> {
+        let request = request.encode();
+
+        let res = self.handle.read_control(&request.setup, buf, self.timeout);
+
+        match res {
+            Ok(n) => Ok(n),
+            Err(rusb::Error::Pipe) => Err(self.rejection()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn control_out(&self, request: Request) -> Result<()> {
+        let request = request.encode();
+
+        let res = self
+            .handle
+            .write_control(&request.setup, request.data(), self.timeout);
+
+        match res {
+            Ok(_) => Ok(()),
+            Err(rusb::Error::Pipe) => Err(self.rejection()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // a stall only says that the request failed; the bridge keeps the reason
+    fn rejection(&self) -> Error {
+        let request = Request::GetLastError.encode();
+        let mut data = [0; LAST_ERROR_LEN];
+
+        let res = self
+            .handle
+            .read_control(&request.setup, &mut data, self.timeout);
+
+        let last = match res {
+            Ok(n) => LastError::decode(&data[..n]).ok(),
+            Err(e) => return Error::Usb(e),
+        };
+
+        match last.and_then(|last| last.error()) {
+            Some(Ok(e)) => Error::Rejected(e),
+            Some(Err(code)) => Error::RejectedUnknown(code),
+            None => Error::Usb(rusb::Error::Pipe),
+        }
+    }
+}
+
+// every request in a range starts again from its first address, so chunks are kept to whole
+// windows
+fn range_chunk_len(range: AddrRange) -> usize {
+    CHUNK_LEN - CHUNK_LEN % usize::from(range.wrap)
+}
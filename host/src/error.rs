@@ -0,0 +1,28 @@
+use thiserror::Error;
+
+pub use card_emu_protocol::BridgeError;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("USB error: {0}")]
+    Usb(#[from] rusb::Error),
+
+    #[error("no bridge found")]
+    NotFound,
+    #[error("{0} bridges found, select one by serial number")]
+    Ambiguous(usize),
+
+    // the request was stalled, and GetLastError gave the reason
+    #[error("rejected by the bridge: {0}")]
+    Rejected(BridgeError),
+    #[error("rejected by the bridge with unknown error {0:#04x}")]
+    RejectedUnknown(u8),
+
+    // the response couldn't be decoded
+    #[error("bad response from the bridge: {0}")]
+    BadResponse(BridgeError),
+    #[error("timed out waiting for the bridge to receive {0} bytes")]
+    RecvTimeout(usize),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
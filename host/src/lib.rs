@@ -0,0 +1,10 @@
+pub use bridge::{Bridge, PID, VID};
+pub use card_emu_protocol::range::AddrRange;
+pub use error::{BridgeError, Error, Result};
+pub use transport::Transport;
+pub use virtual_bridge::VirtualBridge;
+
+mod bridge;
+mod error;
+mod transport;
+mod virtual_bridge;
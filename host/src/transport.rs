@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use card_emu_protocol::Setup;
+use rusb::{Context, DeviceHandle, Direction, Recipient, RequestType};
+
+// the bridge's write endpoint is the host's IN, and its read endpoint the host's OUT
+const BULK_IN_EP: u8 = 0x81;
+const BULK_OUT_EP: u8 = 0x02;
+
+// what Bridge needs from the device, so it can run against hardware or a model of it; errors
+// are libusb's, with a stalled request as Pipe
+pub trait Transport {
+    fn read_control(&self, setup: &Setup, buf: &mut [u8], timeout: Duration)
+    -> rusb::Result<usize>;
+    fn write_control(&self, setup: &Setup, data: &[u8], timeout: Duration) -> rusb::Result<usize>;
+
+    fn read_bulk(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize>;
+    fn write_bulk(&self, data: &[u8], timeout: Duration) -> rusb::Result<usize>;
+}
+
+impl<T: Transport + ?Sized> Transport for &T {
+    fn read_control(
+        &self,
+        setup: &Setup,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        (**self).read_control(setup, buf, timeout)
+    }
+
+    fn write_control(&self, setup: &Setup, data: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        (**self).write_control(setup, data, timeout)
+    }
+
+    fn read_bulk(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        (**self).read_bulk(buf, timeout)
+    }
+
+    fn write_bulk(&self, data: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        (**self).write_bulk(data, timeout)
+    }
+}
+
+impl Transport for DeviceHandle<Context> {
+    fn read_control(
+        &self,
+        setup: &Setup,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> rusb::Result<usize> {
+        DeviceHandle::read_control(
+            self,
+            request_type(Direction::In),
+            setup.request,
+            setup.value,
+            setup.index,
+            buf,
+            timeout,
+        )
+    }
+
+    fn write_control(&self, setup: &Setup, data: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::write_control(
+            self,
+            request_type(Direction::Out),
+            setup.request,
+            setup.value,
+            setup.index,
+            data,
+            timeout,
+        )
+    }
+
+    fn read_bulk(&self, buf: &mut [u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::read_bulk(self, BULK_IN_EP, buf, timeout)
+    }
+
+    fn write_bulk(&self, data: &[u8], timeout: Duration) -> rusb::Result<usize> {
+        DeviceHandle::write_bulk(self, BULK_OUT_EP, data, timeout)
+    }
+}
+
+fn request_type(direction: Direction) -> u8 {
+    rusb::request_type(direction, RequestType::Vendor, Recipient::Device)
+}
@@ -0,0 +1,390 @@
+use std::cell::{Ref, RefCell, RefMut};
+use std::time::Duration;
+
+use card_emu_protocol::Setup;
+use card_emu_protocol::board::MockBoard;
+use card_emu_protocol::bus::{CardBus, MockBus};
+use card_emu_protocol::capture::MockSampler;
+use card_emu_protocol::card::DEBOUNCE_US;
+use card_emu_protocol::handler::{BRIDGE_READ_SIZE, BRIDGE_WRITE_SIZE, CONTROL_IN_LEN, Handler};
+use card_emu_protocol::info::PinMap;
+use card_emu_protocol::program::MockScripts;
+
+use crate::transport::Transport;
+
+// the firmware release this models
+const FIRMWARE_VERSION: [u8; 3] = [0, 1, 0];
+
+// as wired up in the firmware's main.rs
+const PINS: PinMap = PinMap {
+    data_base: 0,
+    data_len: 8,
+    addr_base: 8,
+    addr_len: 8,
+    dir: 16,
+    clk: 17,
+    detect: 18,
+    sense: 19,
+};
+
+const SENSE: u32 = 1 << PINS.sense;
+
+// nothing else drives the virtual GPIOs, so every sample sees them low
+const SAMPLE_LEVELS: u32 = 0;
+
+// bus cycles take no time, but the clock moves on whenever it's read so waits and timeouts end
+const CLOCK_STEP_US: u64 = 1;
+
+type VirtualHandler<Bus> = Handler<Bus, MockBoard, MockSampler, MockScripts>;
+
+// the firmware's request handling, run against a CardBus in memory so host tooling can be tested
+// without hardware. Each request stands in for one pass of the firmware's main loop. Events on
+// the interrupt endpoint aren't read, as nothing on the host reads them yet.
+pub struct VirtualBridge<Bus: CardBus = MockBus> {
+    handler: RefCell<VirtualHandler<Bus>>,
+}
+
+impl VirtualBridge {
+    pub fn new() -> Self {
+        Self::with_bus(MockBus::new())
+    }
+}
+
+impl Default for VirtualBridge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Bus: CardBus> VirtualBridge<Bus> {
+    pub fn with_bus(bus: Bus) -> Self {
+        Self {
+            handler: RefCell::new(Handler::new(
+                bus,
+                MockBoard::new(SENSE, CLOCK_STEP_US),
+                MockSampler::new(SAMPLE_LEVELS),
+                MockScripts::new(),
+                PINS,
+                FIRMWARE_VERSION,
+            )),
+        }
+    }
+
+    pub fn bus(&self) -> Ref<'_, Bus> {
+        Ref::map(self.handler.borrow(), Handler::bus)
+    }
+
+    pub fn bus_mut(&self) -> RefMut<'_, Bus> {
+        RefMut::map(self.handler.borrow_mut(), Handler::bus_mut)
+    }
+
+    // inserts or removes the card, and waits for it to settle
+    pub fn set_present(&self, present: bool) {
+        let mut handler = self.handler.borrow_mut();
+
+        if present {
+            handler.board_mut().pins |= SENSE;
+        } else {
+            handler.board_mut().pins &= !SENSE;
+        }
+
+        handler.update();
+        handler.board().advance(DEBOUNCE_US);
+        handler.update();
+    }
+
+    // whether RebootToUSB has been sent, after which the bridge is gone
+    pub fn rebooted(&self) -> bool {
+        self.handler.borrow().board().rebooted
+    }
+
+    fn attached(&self) -> rusb::Result<RefMut<'_, VirtualHandler<Bus>>> {
+        let handler = self.handler.borrow_mut();
+
+        if handler.board().rebooted {
+            return Err(rusb::Error::NoDevice);
+        }
+
+        Ok(handler)
+    }
+}
+
+impl<Bus: CardBus> Transport for VirtualBridge<Bus> {
+    fn read_control(
+        &self,
+        setup: &Setup,
+        buf: &mut [u8],
+        _timeout: Duration,
+    ) -> rusb::Result<usize> {
+        let mut handler = self.attached()?;
+        handler.update();
+
+        let mut data = [0; CONTROL_IN_LEN];
+
+        // failures have already been recorded by the handler
+        match handler.control_in(setup, &mut data) {
+            // cut short at wLength, as the USB stack does
+            Ok(len) => {
+                let n = len.min(buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                Ok(n)
+            }
+            Err(_) => Err(rusb::Error::Pipe),
+        }
+    }
+
+    fn write_control(&self, setup: &Setup, data: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+        let mut handler = self.attached()?;
+        handler.update();
+
+        match handler.control_out(setup, data) {
+            // the bridge resets before the status stage
+            Ok(()) if handler.board().rebooted => Err(rusb::Error::NoDevice),
+            Ok(()) => Ok(data.len()),
+            Err(_) => Err(rusb::Error::Pipe),
+        }
+    }
+
+    fn read_bulk(&self, buf: &mut [u8], _timeout: Duration) -> rusb::Result<usize> {
+        let mut handler = self.attached()?;
+        let mut done = 0;
+
+        for packet in buf.chunks_mut(BRIDGE_WRITE_SIZE) {
+            let len = handler.pending(packet);
+
+            if len == 0 {
+                break;
+            }
+
+            handler.sent(len);
+            done += len;
+        }
+
+        if done == 0 {
+            return Err(rusb::Error::Timeout);
+        }
+
+        Ok(done)
+    }
+
+    // packets are NAKed while the receive buffer has no room for them
+    fn write_bulk(&self, data: &[u8], _timeout: Duration) -> rusb::Result<usize> {
+        let mut handler = self.attached()?;
+        let mut done = 0;
+
+        for packet in data.chunks(BRIDGE_READ_SIZE) {
+            if !handler.can_receive() {
+                break;
+            }
+
+            handler.receive(packet);
+            done += packet.len();
+        }
+
+        if done == 0 {
+            return Err(rusb::Error::Timeout);
+        }
+
+        Ok(done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use card_emu_protocol::range::AddrRange;
+    use card_emu_protocol::response::LastError;
+    use card_emu_protocol::stream::StreamOp;
+    use card_emu_protocol::{BridgeError, BridgeResult, ControlCommand, Request};
+
+    use super::*;
+    use crate::{Bridge, Error};
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    // every write, in order
+    #[derive(Default)]
+    struct Log(Vec<(u8, u8)>);
+
+    impl CardBus for Log {
+        fn read(&mut self, _addr: u8) -> BridgeResult<u8> {
+            Ok(0)
+        }
+
+        fn write(&mut self, addr: u8, data: u8) -> BridgeResult<()> {
+            self.0.push((addr, data));
+            Ok(())
+        }
+
+        fn flush(&mut self) -> BridgeResult<()> {
+            Ok(())
+        }
+    }
+
+    fn out(model: &impl Transport, request: Request) -> rusb::Result<usize> {
+        let request = request.encode();
+        model.write_control(&request.setup, request.data(), TIMEOUT)
+    }
+
+    fn last_error(model: &impl Transport) -> LastError {
+        let request = Request::GetLastError.encode();
+        let mut data = [0; 5];
+        let n = model
+            .read_control(&request.setup, &mut data, TIMEOUT)
+            .unwrap();
+        LastError::decode(&data[..n]).unwrap()
+    }
+
+    #[test]
+    fn registers_read_back_what_was_written() {
+        let model = VirtualBridge::new();
+        let bridge = Bridge::new(&model, "VIRTUAL");
+
+        bridge.write(0x10, 0xAB).unwrap();
+
+        assert_eq!(bridge.read(0x10).unwrap(), 0xAB);
+        assert_eq!(model.bus().registers[0x10], 0xAB);
+    }
+
+    #[test]
+    fn write_from_buf_consumes_what_it_writes() {
+        let model = VirtualBridge::with_bus(Log::default());
+        let bridge = Bridge::new(&model, "VIRTUAL");
+
+        let data: Vec<u8> = (0..10_000).map(|n| n as u8).collect();
+        bridge.write_from_buf(0x20, &data).unwrap();
+
+        assert_eq!(bridge.recv_len().unwrap(), 0);
+        assert!(model.bus().0.iter().map(|&(_, b)| b).eq(data));
+        assert!(model.bus().0.iter().all(|&(addr, _)| addr == 0x20));
+    }
+
+    #[test]
+    fn write_bits_from_buf_sends_lsb_first_in_bit_0() {
+        let model = VirtualBridge::with_bus(Log::default());
+        let bridge = Bridge::new(&model, "VIRTUAL");
+
+        bridge.write_bits_from_buf(0x30, &[0b1000_0101]).unwrap();
+
+        let bits: Vec<u8> = model.bus().0.iter().map(|&(_, b)| b).collect();
+        assert_eq!(bits, [1, 0, 1, 0, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn reads_are_collected_over_bulk_in() {
+        let model = VirtualBridge::new();
+        let bridge = Bridge::new(&model, "VIRTUAL");
+
+        model.bus_mut().registers[0x20] = 7;
+        assert_eq!(bridge.read_into_buf(0x20, 10_000).unwrap(), [7; 10_000]);
+
+        for (addr, b) in model.bus_mut().registers.iter_mut().enumerate() {
+            *b = addr as u8;
+        }
+
+        // whole windows at a time, so the chunks line up
+        let range = AddrRange::new(0xFE, 1, 3);
+        let data = bridge.read_range(range, 5000).unwrap();
+        assert!(data.iter().enumerate().all(|(n, &b)| b == range.addr(n)));
+        assert_eq!(data[..4], [0xFE, 0xFF, 0x00, 0xFE]);
+        assert_eq!(bridge.send_len().unwrap(), 0);
+    }
+
+    #[test]
+    fn range_writes_follow_the_range() {
+        let model = VirtualBridge::with_bus(Log::default());
+        let bridge = Bridge::new(&model, "VIRTUAL");
+
+        let range = AddrRange::new(0x10, 2, 6);
+        let data: Vec<u8> = (0..5000).map(|n| n as u8).collect();
+        bridge.write_range(range, &data).unwrap();
+
+        let log = &model.bus().0;
+        assert_eq!(log.len(), data.len());
+        assert!(
+            log.iter()
+                .enumerate()
+                .all(|(n, &(addr, b))| addr == 0x10 + (2 * n % 6) as u8 && b == n as u8)
+        );
+    }
+
+    #[test]
+    fn writes_need_the_data_buffered_first() {
+        let model = VirtualBridge::new();
+
+        model.write_bulk(&[1, 2, 3], TIMEOUT).unwrap();
+
+        let request = Request::WriteFromBuf { addr: 0, len: 4 };
+        assert_eq!(out(&model, request), Err(rusb::Error::Pipe));
+        assert_eq!(last_error(&model).error(), Some(Ok(BridgeError::BadLength)));
+
+        // nothing was consumed
+        let bridge = Bridge::new(&model, "VIRTUAL");
+        assert_eq!(bridge.recv_len().unwrap(), 3);
+    }
+
+    #[test]
+    fn requests_fail_without_a_card() {
+        let model = VirtualBridge::new();
+        let bridge = Bridge::new(&model, "VIRTUAL");
+
+        model.set_present(false);
+
+        assert!(matches!(
+            bridge.read(0),
+            Err(Error::Rejected(BridgeError::NoCard))
+        ));
+        assert!(matches!(
+            bridge.write_from_buf(0, &[1]),
+            Err(Error::Rejected(BridgeError::NoCard))
+        ));
+    }
+
+    #[test]
+    fn programs_run_against_the_card() {
+        let model = VirtualBridge::new();
+        model.bus_mut().registers[0x01] = 0x5A;
+
+        // Read 0x01, WriteA 0x02, Emit
+        let program = [0x01, 0x01, 0x03, 0x02, 0x31];
+        model.write_bulk(&program, TIMEOUT).unwrap();
+        out(&model, Request::LoadProgram { len: 5 }).unwrap();
+        out(&model, Request::RunProgram { steps: 0 }).unwrap();
+
+        let mut emitted = [0; 8];
+        assert_eq!(model.read_bulk(&mut emitted, TIMEOUT), Ok(1));
+        assert_eq!(emitted[0], 0x5A);
+        assert_eq!(model.bus().registers[0x02], 0x5A);
+    }
+
+    #[test]
+    fn stream_mode_runs_queued_ops() {
+        let model = VirtualBridge::with_bus(Log::default());
+
+        out(&model, Request::SetStreamMode { enabled: true }).unwrap();
+
+        let header = StreamOp {
+            cmd: ControlCommand::WriteFromBuf,
+            addr: 0x40,
+            bit: 0,
+            remaining: 2,
+        };
+        model.write_bulk(&header.encode(), TIMEOUT).unwrap();
+        model.write_bulk(&[7], TIMEOUT).unwrap();
+        model.write_bulk(&[8], TIMEOUT).unwrap();
+
+        assert_eq!(model.bus().0, [(0x40, 7), (0x40, 8)]);
+    }
+
+    #[test]
+    fn the_bridge_is_gone_after_rebooting() {
+        let model = VirtualBridge::new();
+
+        Bridge::new(&model, "VIRTUAL").reboot_to_bootsel().unwrap();
+
+        assert!(model.rebooted());
+        assert!(matches!(
+            Bridge::new(&model, "VIRTUAL").read(0),
+            Err(Error::Usb(rusb::Error::NoDevice))
+        ));
+    }
+}
@@ -3,8 +3,14 @@ MEMORY {
      * The RP2350 has either external or internal flash.
      *
      * 2 MiB is a safe default here, although a Pico 2 has 4 MiB.
+     * The last 64K of that is kept back for stored scripts.
      */
-    FLASH : ORIGIN = 0x10000000, LENGTH = 2048K
+    FLASH : ORIGIN = 0x10000000, LENGTH = 1984K
+    /*
+     * Stored bus scripts, one 4K sector each. Must match SCRIPT_FLASH_OFFSET
+     * and SCRIPT_FLASH_SIZE in scripts.rs.
+     */
+    SCRIPTS : ORIGIN = 0x101F0000, LENGTH = 64K
     /*
      * RAM consists of 8 banks, SRAM0-SRAM7, with a striped mapping.
      * This is usually good for performance, as it distributes load on
@@ -0,0 +1,52 @@
+use core::cell::Cell;
+
+// what the bridge needs from the board it runs on, besides the card bus
+pub trait Board {
+    // microseconds since power-up
+    fn now_us(&self) -> u64;
+    // the level of every GPIO in bank 0, one bit each
+    fn pins(&self) -> u32;
+    // hands over to the USB bootloader; never returns on hardware
+    fn reboot_to_usb(&mut self);
+}
+
+// a clock that moves on by `step` every time it's read, and pins that only change when told to,
+// for tests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockBoard {
+    pub now: Cell<u64>,
+    pub step: u64,
+    pub pins: u32,
+    pub rebooted: bool,
+}
+
+impl MockBoard {
+    pub const fn new(pins: u32, step: u64) -> Self {
+        Self {
+            now: Cell::new(0),
+            step,
+            pins,
+            rebooted: false,
+        }
+    }
+
+    pub fn advance(&self, us: u64) {
+        self.now.set(self.now.get() + us);
+    }
+}
+
+impl Board for MockBoard {
+    fn now_us(&self) -> u64 {
+        let now = self.now.get();
+        self.advance(self.step);
+        now
+    }
+
+    fn pins(&self) -> u32 {
+        self.pins
+    }
+
+    fn reboot_to_usb(&mut self) {
+        self.rebooted = true;
+    }
+}
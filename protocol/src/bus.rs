@@ -0,0 +1,169 @@
+use crate::error::{BridgeError, BridgeResult};
+use crate::selftest::SelfTest;
+use crate::timing::BusTiming;
+
+// register reads and writes on the card, however they're carried out; the bridge checks that a
+// card is present before using any of these
+pub trait CardBus {
+    fn read(&mut self, addr: u8) -> BridgeResult<u8>;
+    // may be left queued until the next flush or read
+    fn write(&mut self, addr: u8, data: u8) -> BridgeResult<()>;
+    // waits for every queued write to reach the card
+    fn flush(&mut self) -> BridgeResult<()>;
+
+    // `count` writes, the nth given by `write(n)`; a bus may batch them
+    fn write_each(
+        &mut self,
+        count: usize,
+        mut write: impl FnMut(usize) -> (u8, u8),
+    ) -> BridgeResult<()> {
+        for n in 0..count {
+            let (addr, data) = write(n);
+            self.write(addr, data)?;
+        }
+
+        Ok(())
+    }
+
+    // `count` reads, the nth from `addr(n)`, each passed to `out` in order
+    fn read_each(
+        &mut self,
+        count: usize,
+        mut addr: impl FnMut(usize) -> u8,
+        mut out: impl FnMut(u8),
+    ) -> BridgeResult<()> {
+        self.flush()?;
+
+        for n in 0..count {
+            out(self.read(addr(n))?);
+        }
+
+        Ok(())
+    }
+
+    // called on every pass of the main loop
+    fn poll(&mut self) -> BridgeResult<()> {
+        Ok(())
+    }
+
+    // a card has been plugged in, or pulled out and nothing may be driven
+    fn attach(&mut self) {}
+    fn release(&mut self) {}
+
+    fn set_timing(&mut self, _timing: &BusTiming) -> BridgeResult<()> {
+        Ok(())
+    }
+
+    // a bus without lines of its own has nothing to test
+    fn self_test(&mut self) -> BridgeResult<SelfTest> {
+        Ok(SelfTest::default())
+    }
+}
+
+// registers in memory that every write lands in straight away, for tests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockBus {
+    pub registers: [u8; 256],
+    pub reads: usize,
+    pub writes: usize,
+    pub flushes: usize,
+    // returned by every read, write and flush while set
+    pub error: Option<BridgeError>,
+}
+
+impl MockBus {
+    pub const fn new() -> Self {
+        Self {
+            registers: [0; 256],
+            reads: 0,
+            writes: 0,
+            flushes: 0,
+            error: None,
+        }
+    }
+
+    fn check(&self) -> BridgeResult<()> {
+        match self.error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for MockBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CardBus for MockBus {
+    fn read(&mut self, addr: u8) -> BridgeResult<u8> {
+        self.check()?;
+        self.reads += 1;
+        Ok(self.registers[usize::from(addr)])
+    }
+
+    fn write(&mut self, addr: u8, data: u8) -> BridgeResult<()> {
+        self.check()?;
+        self.writes += 1;
+        self.registers[usize::from(addr)] = data;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> BridgeResult<()> {
+        self.check()?;
+        self.flushes += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_each_writes_in_order() {
+        let mut bus = MockBus::new();
+
+        bus.write_each(4, |n| (0x10 + n as u8, n as u8 + 1))
+            .unwrap();
+
+        assert_eq!(bus.registers[0x10..0x14], [1, 2, 3, 4]);
+        assert_eq!(bus.writes, 4);
+    }
+
+    #[test]
+    fn read_each_flushes_first() {
+        let mut bus = MockBus::new();
+        bus.registers[0x20] = 0xAA;
+        bus.registers[0x21] = 0xBB;
+
+        let mut out = [0; 4];
+        let mut pos = 0;
+        bus.read_each(
+            4,
+            |n| 0x20 + (n % 2) as u8,
+            |b| {
+                out[pos] = b;
+                pos += 1;
+            },
+        )
+        .unwrap();
+
+        assert_eq!(out, [0xAA, 0xBB, 0xAA, 0xBB]);
+        assert_eq!(bus.flushes, 1);
+    }
+
+    #[test]
+    fn errors_stop_a_batch() {
+        let mut bus = MockBus::new();
+        bus.error = Some(BridgeError::WriteTimeout);
+
+        assert_eq!(
+            bus.write_each(4, |n| (n as u8, 0xFF)),
+            Err(BridgeError::WriteTimeout)
+        );
+        assert_eq!(bus.writes, 0);
+        assert_eq!(bus.registers, [0; 256]);
+    }
+}
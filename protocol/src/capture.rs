@@ -0,0 +1,278 @@
+use crate::error::{BridgeError, BridgeResult};
+
+// samples held at once, each every GPIO in bank 0
+pub const CAPTURE_LEN: usize = 8192;
+
+// clock divisor (16.8 fixed point, big-endian integer part), sample count (big-endian), trigger
+// mask and value (big-endian)
+pub const CAPTURE_CONFIG_LEN: usize = 2 + 1 + 2 + 4 + 4;
+
+// samples are sent as sigrok "raw binary logic data" with 24 channels (GPIO0 is channel 0)
+pub const CAPTURE_UNIT_SIZE: usize = 3;
+
+// state, samples captured (big-endian)
+pub const CAPTURE_STATUS_LEN: usize = 1 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureConfig {
+    pub clock_int: u16,
+    pub clock_frac: u8,
+    pub samples: usize,
+    // the capture starts once (pins & mask) == value; a zero mask starts it straight away, and
+    // one with more runs of neighbouring bits than the sampler can check is refused
+    pub trigger_mask: u32,
+    pub trigger_value: u32,
+}
+
+impl CaptureConfig {
+    pub fn decode(data: &[u8]) -> BridgeResult<Self> {
+        if data.len() != CAPTURE_CONFIG_LEN {
+            return Err(BridgeError::BadLength);
+        }
+
+        let config = Self {
+            clock_int: u16::from_be_bytes([data[0], data[1]]),
+            clock_frac: data[2],
+            samples: usize::from(u16::from_be_bytes([data[3], data[4]])),
+            trigger_mask: u32::from_be_bytes([data[5], data[6], data[7], data[8]]),
+            trigger_value: u32::from_be_bytes([data[9], data[10], data[11], data[12]]),
+        };
+
+        if config.clock_int == 0 || config.samples == 0 || config.samples > CAPTURE_LEN {
+            return Err(BridgeError::BadArgument);
+        }
+
+        Ok(config)
+    }
+
+    pub fn encode(&self) -> [u8; CAPTURE_CONFIG_LEN] {
+        let mut buf = [0; CAPTURE_CONFIG_LEN];
+
+        buf[0..2].copy_from_slice(&self.clock_int.to_be_bytes());
+        buf[2] = self.clock_frac;
+        buf[3..5].copy_from_slice(&(self.samples as u16).to_be_bytes());
+        buf[5..9].copy_from_slice(&self.trigger_mask.to_be_bytes());
+        buf[9..13].copy_from_slice(&self.trigger_value.to_be_bytes());
+
+        buf
+    }
+
+    pub fn triggered(&self, pins: u32) -> bool {
+        pins & self.trigger_mask == self.trigger_value & self.trigger_mask
+    }
+
+    // the trigger bits that must be low
+    pub fn trigger_low(&self) -> u32 {
+        self.trigger_mask & !self.trigger_value
+    }
+
+    // and high
+    pub fn trigger_high(&self) -> u32 {
+        self.trigger_mask & self.trigger_value
+    }
+}
+
+// neighbouring trigger bits, `skip` bits past the end of the run before
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TriggerRun {
+    pub skip: u8,
+    pub len: u8,
+}
+
+// the runs of set bits in `bits`, LSB first, so a sampler can check a whole run at once
+pub fn trigger_runs(bits: u32) -> impl Iterator<Item = TriggerRun> {
+    let mut rest = bits;
+
+    core::iter::from_fn(move || {
+        if rest == 0 {
+            return None;
+        }
+
+        let skip = rest.trailing_zeros();
+        let len = (rest >> skip).trailing_ones();
+        rest = rest.checked_shr(skip + len).unwrap_or(0);
+
+        Some(TriggerRun {
+            skip: skip as u8,
+            len: len as u8,
+        })
+    })
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CaptureState {
+    Idle = 0x00,
+    Armed = 0x01,
+    Running = 0x02,
+    Done = 0x03,
+}
+
+impl TryFrom<u8> for CaptureState {
+    type Error = u8;
+
+    fn try_from(value: u8) -> core::result::Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Idle),
+            0x01 => Ok(Self::Armed),
+            0x02 => Ok(Self::Running),
+            0x03 => Ok(Self::Done),
+
+            e => Err(e),
+        }
+    }
+}
+
+// the response to GetCaptureStatus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CaptureStatus {
+    pub state: CaptureState,
+    // only non-zero once Done
+    pub samples: u32,
+}
+
+impl CaptureStatus {
+    pub fn decode(data: &[u8]) -> BridgeResult<Self> {
+        if data.len() != CAPTURE_STATUS_LEN {
+            return Err(BridgeError::BadLength);
+        }
+
+        Ok(Self {
+            state: CaptureState::try_from(data[0]).map_err(|_| BridgeError::BadArgument)?,
+            samples: u32::from_be_bytes([data[1], data[2], data[3], data[4]]),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; CAPTURE_STATUS_LEN] {
+        let [b0, b1, b2, b3] = self.samples.to_be_bytes();
+        [self.state as u8, b0, b1, b2, b3]
+    }
+}
+
+pub fn encode_sample(sample: u32) -> [u8; CAPTURE_UNIT_SIZE] {
+    let [b0, b1, b2, _] = sample.to_le_bytes();
+    [b0, b1, b2]
+}
+
+// records the pins for a capture, from the first sample that matches its trigger; the trigger
+// has to be matched by the sampler itself, as anything slower misses patterns that only last a
+// bus cycle
+pub trait Sampler {
+    // starts waiting for the trigger, replacing whatever capture was in progress; fails with
+    // BadArgument if the trigger is more than it can match
+    fn arm(&mut self, config: &CaptureConfig) -> BridgeResult<()>;
+    fn stop(&mut self);
+    // Armed until the trigger is seen, Running while samples arrive, then Done once they've
+    // filled, after which it stops by itself
+    fn poll(&mut self) -> CaptureState;
+    // everything recorded, once Done
+    fn samples(&mut self) -> &[u32];
+}
+
+// pins held at `level`, which a capture is filled with one poll after it triggers, for tests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockSampler {
+    pub level: u32,
+    config: Option<CaptureConfig>,
+    samples: [u32; CAPTURE_LEN],
+    state: CaptureState,
+}
+
+impl MockSampler {
+    pub const fn new(level: u32) -> Self {
+        Self {
+            level,
+            config: None,
+            samples: [0; CAPTURE_LEN],
+            state: CaptureState::Idle,
+        }
+    }
+}
+
+impl Sampler for MockSampler {
+    fn arm(&mut self, config: &CaptureConfig) -> BridgeResult<()> {
+        self.config = Some(*config);
+        self.state = CaptureState::Armed;
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.state = CaptureState::Idle;
+    }
+
+    fn poll(&mut self) -> CaptureState {
+        let Some(config) = self.config else {
+            return self.state;
+        };
+
+        self.state = match self.state {
+            CaptureState::Armed if config.triggered(self.level) => CaptureState::Running,
+            CaptureState::Running => {
+                self.samples[..config.samples].fill(self.level);
+                CaptureState::Done
+            }
+            state => state,
+        };
+
+        self.state
+    }
+
+    fn samples(&mut self) -> &[u32] {
+        match (self.state, self.config) {
+            (CaptureState::Done, Some(config)) => &self.samples[..config.samples],
+            _ => &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runs<const N: usize>(bits: u32) -> [TriggerRun; N] {
+        let mut out = [TriggerRun { skip: 0, len: 0 }; N];
+        let mut n = 0;
+
+        for run in trigger_runs(bits) {
+            out[n] = run;
+            n += 1;
+        }
+
+        assert_eq!(n, N);
+        out
+    }
+
+    #[test]
+    fn runs_skip_the_gaps_between_them() {
+        assert_eq!(
+            runs(0b0111_0000_0110),
+            [
+                TriggerRun { skip: 1, len: 2 },
+                TriggerRun { skip: 5, len: 3 }
+            ]
+        );
+    }
+
+    #[test]
+    fn runs_reach_the_top_bit() {
+        assert_eq!(runs(u32::MAX), [TriggerRun { skip: 0, len: 32 }]);
+        assert_eq!(runs(1 << 31), [TriggerRun { skip: 31, len: 1 }]);
+        assert_eq!(runs::<0>(0), []);
+    }
+
+    #[test]
+    fn triggers_split_into_low_and_high_bits() {
+        let config = CaptureConfig {
+            clock_int: 1,
+            clock_frac: 0,
+            samples: 1,
+            trigger_mask: 0xFF00,
+            trigger_value: 0x5AFF,
+        };
+
+        assert_eq!(config.trigger_low(), 0xA500);
+        assert_eq!(config.trigger_high(), 0x5A00);
+        assert!(config.triggered(0x005A_5A00));
+        assert!(!config.triggered(0x5B00));
+    }
+}
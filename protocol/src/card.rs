@@ -0,0 +1,64 @@
+// how long the sense input has to hold a new level before it counts
+pub const DEBOUNCE_US: u64 = 10_000;
+
+pub struct CardDetect {
+    present: bool,
+    level: bool,
+    since: u64,
+}
+
+impl CardDetect {
+    pub fn new(present: bool) -> Self {
+        Self {
+            present,
+            level: present,
+            since: 0,
+        }
+    }
+
+    pub fn present(&self) -> bool {
+        self.present
+    }
+
+    // returns the new state once a change has settled
+    pub fn update(&mut self, level: bool, now: u64) -> Option<bool> {
+        if level != self.level {
+            self.level = level;
+            self.since = now;
+        }
+
+        if self.level != self.present && now - self.since >= DEBOUNCE_US {
+            self.present = self.level;
+            return Some(self.present);
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_once_settled() {
+        let mut card = CardDetect::new(true);
+
+        assert_eq!(card.update(false, 100), None);
+        assert_eq!(card.update(false, 100 + DEBOUNCE_US - 1), None);
+        assert_eq!(card.update(false, 100 + DEBOUNCE_US), Some(false));
+        assert_eq!(card.update(false, 200 + DEBOUNCE_US), None);
+        assert!(!card.present());
+    }
+
+    #[test]
+    fn ignores_bounces() {
+        let mut card = CardDetect::new(false);
+
+        assert_eq!(card.update(true, 0), None);
+        assert_eq!(card.update(false, DEBOUNCE_US / 2), None);
+        assert_eq!(card.update(true, DEBOUNCE_US), None);
+        assert_eq!(card.update(true, 2 * DEBOUNCE_US - 1), None);
+        assert_eq!(card.update(true, 2 * DEBOUNCE_US), Some(true));
+    }
+}
@@ -0,0 +1,130 @@
+// vendor requests, in bRequest; these values must not change
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    Write = 0x00,
+    Read = 0x01,
+    WaitFor = 0x02,
+
+    WriteFromBuf = 0x10,
+    ReadIntoBuf = 0x11,
+    WriteBitsFromBuf = 0x12,
+    ReadBitsIntoBuf = 0x13,
+    ReadRangeIntoBuf = 0x14,
+    WriteRangeFromBuf = 0x15,
+    ScatterGather = 0x16,
+
+    SetStreamMode = 0x20,
+    SetWatch = 0x21,
+    SetBusTiming = 0x22,
+
+    LoadProgram = 0x30,
+    RunProgram = 0x31,
+    StoreScript = 0x32,
+    RunScript = 0x33,
+    EraseScript = 0x34,
+
+    SelfTest = 0x40,
+
+    StartCapture = 0x50,
+    ReadCapture = 0x51,
+    StopCapture = 0x52,
+
+    GetRecvLen = 0x80,
+    GetSendLen = 0x81,
+    GetLastError = 0x82,
+    GetInfo = 0x83,
+    GetScriptInfo = 0x84,
+    GetBusTiming = 0x85,
+    GetCardStatus = 0x86,
+    GetCaptureStatus = 0x87,
+
+    RebootToUSB = 0xFF,
+}
+
+impl TryFrom<u8> for ControlCommand {
+    type Error = u8;
+
+    fn try_from(value: u8) -> core::result::Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Write),
+            0x01 => Ok(Self::Read),
+            0x02 => Ok(Self::WaitFor),
+
+            0x10 => Ok(Self::WriteFromBuf),
+            0x11 => Ok(Self::ReadIntoBuf),
+            0x12 => Ok(Self::WriteBitsFromBuf),
+            0x13 => Ok(Self::ReadBitsIntoBuf),
+            0x14 => Ok(Self::ReadRangeIntoBuf),
+            0x15 => Ok(Self::WriteRangeFromBuf),
+            0x16 => Ok(Self::ScatterGather),
+
+            0x20 => Ok(Self::SetStreamMode),
+            0x21 => Ok(Self::SetWatch),
+            0x22 => Ok(Self::SetBusTiming),
+
+            0x30 => Ok(Self::LoadProgram),
+            0x31 => Ok(Self::RunProgram),
+            0x32 => Ok(Self::StoreScript),
+            0x33 => Ok(Self::RunScript),
+            0x34 => Ok(Self::EraseScript),
+
+            0x40 => Ok(Self::SelfTest),
+
+            0x50 => Ok(Self::StartCapture),
+            0x51 => Ok(Self::ReadCapture),
+            0x52 => Ok(Self::StopCapture),
+
+            0x80 => Ok(Self::GetRecvLen),
+            0x81 => Ok(Self::GetSendLen),
+            0x82 => Ok(Self::GetLastError),
+            0x83 => Ok(Self::GetInfo),
+            0x84 => Ok(Self::GetScriptInfo),
+            0x85 => Ok(Self::GetBusTiming),
+            0x86 => Ok(Self::GetCardStatus),
+            0x87 => Ok(Self::GetCaptureStatus),
+
+            0xFF => Ok(Self::RebootToUSB),
+
+            e => Err(e),
+        }
+    }
+}
+
+impl ControlCommand {
+    // device to host, with any response in the data stage
+    pub fn is_in(&self) -> bool {
+        matches!(
+            self,
+            Self::Read
+                | Self::WaitFor
+                | Self::SelfTest
+                | Self::GetRecvLen
+                | Self::GetSendLen
+                | Self::GetLastError
+                | Self::GetInfo
+                | Self::GetScriptInfo
+                | Self::GetBusTiming
+                | Self::GetCardStatus
+                | Self::GetCaptureStatus
+        )
+    }
+}
+
+// the fields of a vendor request's setup packet that carry arguments
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Setup {
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+}
+
+// bus cycles (and most requests) carry the address in the high byte and data in the low byte
+pub fn pack(addr: u8, data: u8) -> u16 {
+    u16::from_be_bytes([addr, data])
+}
+
+pub fn unpack(value: u16) -> (u8, u8) {
+    let [addr, data] = value.to_be_bytes();
+    (addr, data)
+}
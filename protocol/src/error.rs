@@ -0,0 +1,76 @@
+use core::fmt;
+
+// reported to the host by GetLastError, so these values must not change
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BridgeError {
+    ReadRequestTimeout = 0x01,
+    ReadResponseTimeout = 0x02,
+    WriteTimeout = 0x03,
+    FlushTimeout = 0x04,
+    DmaTimeout = 0x05,
+    DmaBusy = 0x06,
+    NoCard = 0x07,
+
+    UnknownCommand = 0x10,
+    WrongDirection = 0x11,
+    BadLength = 0x12,
+    BufferFull = 0x13,
+    StreamActive = 0x14,
+    BadStreamFrame = 0x15,
+    BadArgument = 0x16,
+
+    Usb = 0x20,
+
+    VmStepLimit = 0x30,
+    VmBadInstruction = 0x31,
+    VmTimeout = 0x32,
+
+    NoScript = 0x40,
+
+    CaptureNotReady = 0x50,
+}
+
+impl TryFrom<u8> for BridgeError {
+    type Error = u8;
+
+    fn try_from(value: u8) -> core::result::Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(Self::ReadRequestTimeout),
+            0x02 => Ok(Self::ReadResponseTimeout),
+            0x03 => Ok(Self::WriteTimeout),
+            0x04 => Ok(Self::FlushTimeout),
+            0x05 => Ok(Self::DmaTimeout),
+            0x06 => Ok(Self::DmaBusy),
+            0x07 => Ok(Self::NoCard),
+
+            0x10 => Ok(Self::UnknownCommand),
+            0x11 => Ok(Self::WrongDirection),
+            0x12 => Ok(Self::BadLength),
+            0x13 => Ok(Self::BufferFull),
+            0x14 => Ok(Self::StreamActive),
+            0x15 => Ok(Self::BadStreamFrame),
+            0x16 => Ok(Self::BadArgument),
+
+            0x20 => Ok(Self::Usb),
+
+            0x30 => Ok(Self::VmStepLimit),
+            0x31 => Ok(Self::VmBadInstruction),
+            0x32 => Ok(Self::VmTimeout),
+
+            0x40 => Ok(Self::NoScript),
+
+            0x50 => Ok(Self::CaptureNotReady),
+
+            e => Err(e),
+        }
+    }
+}
+
+impl fmt::Display for BridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} ({:#04x})", self, *self as u8)
+    }
+}
+
+pub type BridgeResult<T> = core::result::Result<T, BridgeError>;
@@ -0,0 +1,128 @@
+use crate::error::{BridgeError, BridgeResult};
+
+// kind, then up to three bytes of detail
+pub const EVENT_LEN: usize = 4;
+
+// sent to the host on the interrupt endpoint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    // address, old value, new value (both masked)
+    RegisterChanged { addr: u8, old: u8, new: u8 },
+
+    CardInserted,
+    CardRemoved,
+
+    // the bridge has consumed everything the host sent
+    RecvEmpty,
+    // the host has taken everything the bridge queued
+    SendEmpty,
+    // the host is being NAKed until the bridge catches up
+    RecvFull,
+
+    // the capture buffer has filled
+    CaptureDone,
+
+    // code, then the low half of the error count (big-endian)
+    Error { code: BridgeError, count: u16 },
+    // events dropped because the queue was full (big-endian, saturating)
+    Lost { count: u16 },
+}
+
+impl Event {
+    pub fn decode(data: &[u8]) -> BridgeResult<Self> {
+        let &[kind, a, b, c] = data else {
+            return Err(BridgeError::BadLength);
+        };
+
+        match kind {
+            0x01 => Ok(Self::RegisterChanged {
+                addr: a,
+                old: b,
+                new: c,
+            }),
+
+            0x10 => Ok(Self::CardInserted),
+            0x11 => Ok(Self::CardRemoved),
+
+            0x20 => Ok(Self::RecvEmpty),
+            0x21 => Ok(Self::SendEmpty),
+            0x22 => Ok(Self::RecvFull),
+
+            0x40 => Ok(Self::CaptureDone),
+
+            0x30 => Ok(Self::Error {
+                code: BridgeError::try_from(a).map_err(|_| BridgeError::BadArgument)?,
+                count: u16::from_be_bytes([b, c]),
+            }),
+            0x31 => Ok(Self::Lost {
+                count: u16::from_be_bytes([b, c]),
+            }),
+
+            _ => Err(BridgeError::BadArgument),
+        }
+    }
+
+    pub fn encode(&self) -> [u8; EVENT_LEN] {
+        match *self {
+            Self::RegisterChanged { addr, old, new } => [0x01, addr, old, new],
+
+            Self::CardInserted => [0x10, 0, 0, 0],
+            Self::CardRemoved => [0x11, 0, 0, 0],
+
+            Self::RecvEmpty => [0x20, 0, 0, 0],
+            Self::SendEmpty => [0x21, 0, 0, 0],
+            Self::RecvFull => [0x22, 0, 0, 0],
+
+            Self::CaptureDone => [0x40, 0, 0, 0],
+
+            Self::Error { code, count } => {
+                let [hi, lo] = count.to_be_bytes();
+                [0x30, code as u8, hi, lo]
+            }
+            Self::Lost { count } => {
+                let [hi, lo] = count.to_be_bytes();
+                [0x31, 0, hi, lo]
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_event_round_trips() {
+        let events = [
+            Event::RegisterChanged {
+                addr: 0x10,
+                old: 0x01,
+                new: 0x03,
+            },
+            Event::CardInserted,
+            Event::CardRemoved,
+            Event::RecvEmpty,
+            Event::SendEmpty,
+            Event::RecvFull,
+            Event::CaptureDone,
+            Event::Error {
+                code: BridgeError::NoCard,
+                count: 0x1234,
+            },
+            Event::Lost { count: 7 },
+        ];
+
+        for event in events {
+            assert_eq!(Event::decode(&event.encode()), Ok(event));
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_events() {
+        assert_eq!(
+            Event::decode(&[0xEE, 0, 0, 0]),
+            Err(BridgeError::BadArgument)
+        );
+        assert_eq!(Event::decode(&[0x10, 0, 0]), Err(BridgeError::BadLength));
+    }
+}
@@ -0,0 +1,1498 @@
+use crate::board::Board;
+use crate::bus::CardBus;
+use crate::capture::{
+    CAPTURE_STATUS_LEN, CAPTURE_UNIT_SIZE, CaptureConfig, CaptureState, CaptureStatus, Sampler,
+    encode_sample,
+};
+use crate::card::CardDetect;
+use crate::command::{ControlCommand, Setup};
+use crate::error::{BridgeError, BridgeResult};
+use crate::event::{EVENT_LEN, Event};
+use crate::info::{INFO_LEN, Info, PROTOCOL_VERSION, PinMap, command_bitmap};
+use crate::program::{
+    DEFAULT_STEP_LIMIT, PROGRAM_SIZE, SCRIPT_HEADER_LEN, ScriptHeader, ScriptStore,
+};
+use crate::range::AddrRange;
+use crate::request::{Request, SCATTER_ENTRY_LEN, WAIT_FOR_UNIT_US};
+use crate::response::{
+    BUFFER_LEN_LEN, CARD_STATUS_LEN, CardStatus, LAST_ERROR_LEN, LastError, WAIT_FOR_LEN,
+    WaitForResult, encode_buffer_len,
+};
+use crate::ring::RingBuffer;
+use crate::selftest::SELF_TEST_LEN;
+use crate::stream::{STREAM_HEADER_LEN, StreamOp};
+use crate::timing::{BUS_TIMING_LEN, BusTiming};
+use crate::vm::{Machine, Vm};
+
+// maximum size allowed for bulk endpoints
+pub const BRIDGE_WRITE_SIZE: usize = 64;
+pub const BRIDGE_READ_SIZE: usize = 64;
+
+// queued bytes either side of the bulk endpoints
+pub const SEND_BUFFER_SIZE: usize = 16 * 1024;
+pub const RECV_BUFFER_SIZE: usize = 16 * 1024;
+
+// events waiting for the interrupt endpoint
+const EVENT_QUEUE_SIZE: usize = 64 * EVENT_LEN;
+
+// the longest response to an IN request
+pub const CONTROL_IN_LEN: usize = INFO_LEN;
+
+const _: () = assert!(
+    BUFFER_LEN_LEN <= CONTROL_IN_LEN
+        && WAIT_FOR_LEN <= CONTROL_IN_LEN
+        && LAST_ERROR_LEN <= CONTROL_IN_LEN
+        && CARD_STATUS_LEN <= CONTROL_IN_LEN
+        && CAPTURE_STATUS_LEN <= CONTROL_IN_LEN
+        && SCRIPT_HEADER_LEN <= CONTROL_IN_LEN
+        && SELF_TEST_LEN <= CONTROL_IN_LEN
+        && BUS_TIMING_LEN <= CONTROL_IN_LEN
+);
+
+// longest a bytecode program may run for; it's only checked between instructions, so a run can
+// overshoot by a Delay, and must still finish well before the host gives up on the request
+pub const VM_TIMEOUT_US: u64 = 400_000;
+
+// a register polled in the background, reporting RegisterChanged whenever the bits in `mask`
+// change
+#[derive(Debug, Clone, Copy)]
+struct Watch {
+    addr: u8,
+    mask: u8,
+    interval_us: u64,
+    next: u64,
+    last: Option<u8>,
+}
+
+// everything the bridge does with a request once it has arrived, and the buffers either side of
+// it, with nothing tied to USB or to the RP2350; the firmware feeds it from its endpoints, and the
+// host library's VirtualBridge from memory
+pub struct Handler<Bus, B, S, Sc>
+where
+    Bus: CardBus,
+    B: Board,
+    S: Sampler,
+    Sc: ScriptStore,
+{
+    bus: Bus,
+    board: B,
+    sampler: S,
+    scripts: Sc,
+
+    pins: PinMap,
+    firmware_version: [u8; 3],
+
+    card: CardDetect,
+    timing: BusTiming,
+
+    capture: CaptureState,
+
+    send_buffer: RingBuffer<SEND_BUFFER_SIZE>,
+    recv_buffer: RingBuffer<RECV_BUFFER_SIZE>,
+    recv_full: bool,
+
+    event_queue: RingBuffer<EVENT_QUEUE_SIZE>,
+    events_lost: u16,
+    watch: Option<Watch>,
+
+    stream_mode: bool,
+    stream_op: Option<StreamOp>,
+
+    last_error: Option<BridgeError>,
+    error_count: u32,
+
+    program: [u8; PROGRAM_SIZE],
+    program_len: usize,
+    vm_deadline: u64,
+}
+
+impl<Bus, B, S, Sc> Handler<Bus, B, S, Sc>
+where
+    Bus: CardBus,
+    B: Board,
+    S: Sampler,
+    Sc: ScriptStore,
+{
+    pub fn new(
+        bus: Bus,
+        board: B,
+        sampler: S,
+        scripts: Sc,
+        pins: PinMap,
+        firmware_version: [u8; 3],
+    ) -> Self {
+        let present = board.pins() & (1 << pins.sense) != 0;
+
+        let mut handler = Self {
+            bus,
+            board,
+            sampler,
+            scripts,
+            pins,
+            firmware_version,
+            card: CardDetect::new(present),
+            timing: BusTiming::DEFAULT,
+            capture: CaptureState::Idle,
+            send_buffer: RingBuffer::new(),
+            recv_buffer: RingBuffer::new(),
+            recv_full: false,
+            event_queue: RingBuffer::new(),
+            events_lost: 0,
+            watch: None,
+            stream_mode: false,
+            stream_op: None,
+            last_error: None,
+            error_count: 0,
+            program: [0; PROGRAM_SIZE],
+            program_len: 0,
+            vm_deadline: 0,
+        };
+
+        if !present {
+            handler.bus.release();
+        }
+
+        handler
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+
+    pub fn board(&self) -> &B {
+        &self.board
+    }
+
+    pub fn board_mut(&mut self) -> &mut B {
+        &mut self.board
+    }
+
+    pub fn sampler_mut(&mut self) -> &mut S {
+        &mut self.sampler
+    }
+
+    // the host has reset the device, so nothing it queued or asked for is wanted any more
+    pub fn reset(&mut self) {
+        self.send_buffer.clear();
+        self.recv_buffer.clear();
+        self.recv_full = false;
+        self.event_queue.clear();
+        self.events_lost = 0;
+        self.watch = None;
+        self.stream_mode = false;
+        self.stream_op = None;
+    }
+
+    // leaves the response in `out`, returning its length; a failed request has been recorded by
+    // the time this returns, and should be stalled
+    pub fn control_in(
+        &mut self,
+        setup: &Setup,
+        out: &mut [u8; CONTROL_IN_LEN],
+    ) -> BridgeResult<usize> {
+        let res = self.respond(setup, out);
+
+        if let Err(e) = res {
+            self.fail(e);
+        }
+
+        res
+    }
+
+    // as control_in
+    pub fn control_out(&mut self, setup: &Setup, data: &[u8]) -> BridgeResult<()> {
+        let res = self.perform(setup, data);
+
+        if let Err(e) = res {
+            self.fail(e);
+        }
+
+        res
+    }
+
+    // whether there's room for another packet from the host; if not, it should be left in the
+    // endpoint (so the host is NAKed) until there is
+    pub fn can_receive(&mut self) -> bool {
+        if self.recv_buffer.free() < BRIDGE_READ_SIZE {
+            if !self.recv_full {
+                self.recv_full = true;
+                self.queue_event(Event::RecvFull);
+            }
+            return false;
+        }
+
+        self.recv_full = false;
+        true
+    }
+
+    // a packet from the host, once can_receive has made room for it
+    pub fn receive(&mut self, packet: &[u8]) {
+        self.recv_buffer.extend(packet);
+
+        if self.stream_mode && !packet.is_empty() {
+            self.run_stream();
+        }
+    }
+
+    // what's waiting for the host, left queued until it has been sent
+    pub fn pending(&self, out: &mut [u8]) -> usize {
+        self.send_buffer.peek(out)
+    }
+
+    // the host has taken `amount` bytes, so anything that was waiting for space can continue
+    pub fn sent(&mut self, amount: usize) {
+        self.send_buffer.consume(amount);
+
+        if amount > 0 && self.send_buffer.is_empty() {
+            self.queue_event(Event::SendEmpty);
+        }
+
+        if self.stream_mode && amount > 0 {
+            self.run_stream();
+        }
+    }
+
+    // events waiting for the interrupt endpoint, left queued until they've been sent
+    pub fn pending_events(&mut self, out: &mut [u8]) -> usize {
+        if self.events_lost > 0 && self.event_queue.free() >= EVENT_LEN {
+            let lost = Event::Lost {
+                count: self.events_lost,
+            };
+            self.event_queue.extend(&lost.encode());
+            self.events_lost = 0;
+        }
+
+        self.event_queue.peek(out)
+    }
+
+    pub fn events_sent(&mut self, amount: usize) {
+        self.event_queue.consume(amount);
+    }
+
+    // before any host connects, so anything it emits is dropped when the host resets the device
+    pub fn run_boot_script(&mut self) {
+        if let Some(slot) = self.scripts.autorun()
+            && let Err(e) = self.run_script(slot, DEFAULT_STEP_LIMIT)
+        {
+            self.fail(e);
+        }
+    }
+
+    // called on every pass of the main loop
+    pub fn update(&mut self) {
+        if let Err(e) = self.bus.poll() {
+            self.fail(e);
+        }
+
+        let level = self.board.pins() & (1 << self.pins.sense) != 0;
+        let now = self.board.now_us();
+
+        match self.card.update(level, now) {
+            Some(true) => {
+                self.bus.attach();
+                self.queue_event(Event::CardInserted);
+            }
+            Some(false) => {
+                self.bus.release();
+                self.queue_event(Event::CardRemoved);
+            }
+            None => {}
+        }
+
+        if self.card.present()
+            && let Some(watch) = self.watch
+            && self.expired(watch.next)
+        {
+            self.poll_watch(watch);
+        }
+
+        self.update_capture();
+    }
+
+    pub fn fail(&mut self, e: BridgeError) {
+        self.last_error = Some(e);
+        self.error_count = self.error_count.wrapping_add(1);
+
+        self.queue_event(Event::Error {
+            code: e,
+            count: self.error_count as u16,
+        });
+    }
+
+    fn respond(&mut self, setup: &Setup, out: &mut [u8; CONTROL_IN_LEN]) -> BridgeResult<usize> {
+        match Request::decode_in(setup)? {
+            Request::Read { addr } => reply(out, &[self.bus_read(addr)?]),
+
+            Request::WaitFor {
+                addr,
+                mask,
+                expected,
+                timeout,
+            } => {
+                let result =
+                    self.wait_for(addr, mask, expected, u64::from(timeout) * WAIT_FOR_UNIT_US)?;
+                reply(out, &result.encode())
+            }
+
+            Request::SelfTest => reply(out, &self.bus.self_test()?.encode()),
+
+            Request::GetRecvLen => reply(out, &encode_buffer_len(self.recv_buffer.len())),
+
+            Request::GetSendLen => reply(out, &encode_buffer_len(self.send_buffer.len())),
+
+            Request::GetLastError => {
+                let last = LastError {
+                    code: self.last_error.take().map_or(0, |e| e as u8),
+                    count: self.error_count,
+                };
+                reply(out, &last.encode())
+            }
+
+            Request::GetInfo => {
+                let info = Info {
+                    protocol_version: PROTOCOL_VERSION,
+                    firmware_version: self.firmware_version,
+                    read_packet_size: BRIDGE_READ_SIZE as u16,
+                    write_packet_size: BRIDGE_WRITE_SIZE as u16,
+                    recv_buffer_size: RECV_BUFFER_SIZE as u32,
+                    send_buffer_size: SEND_BUFFER_SIZE as u32,
+                    pins: self.pins,
+                    commands: command_bitmap(),
+                };
+                reply(out, &info.encode())
+            }
+
+            Request::GetScriptInfo { slot } => {
+                reply(out, &self.scripts.header(usize::from(slot))?.encode())
+            }
+
+            Request::GetBusTiming => reply(out, &self.timing.encode()),
+
+            Request::GetCardStatus => {
+                let status = CardStatus {
+                    present: self.card.present(),
+                };
+                reply(out, &status.encode())
+            }
+
+            Request::GetCaptureStatus => {
+                let status = CaptureStatus {
+                    state: self.capture,
+                    samples: self.captured() as u32,
+                };
+                reply(out, &status.encode())
+            }
+
+            // decode_in only gives IN requests
+            _ => Err(BridgeError::WrongDirection),
+        }
+    }
+
+    fn perform(&mut self, setup: &Setup, data: &[u8]) -> BridgeResult<()> {
+        match Request::decode_out(setup, data)? {
+            Request::RebootToUSB => {
+                self.board.reboot_to_usb();
+                Ok(())
+            }
+
+            Request::Write { addr, data } => {
+                self.bus_write(addr, data)?;
+                self.bus_flush()
+            }
+
+            Request::WriteFromBuf { addr, len } => {
+                let to_write = usize::from(len);
+
+                if self.stream_mode {
+                    Err(BridgeError::StreamActive)
+                } else if to_write > self.recv_buffer.len() {
+                    Err(BridgeError::BadLength)
+                } else {
+                    self.write_from_buf(addr, to_write)
+                }
+            }
+
+            Request::WriteBitsFromBuf { addr, len } => {
+                let to_write = usize::from(len);
+
+                if self.stream_mode {
+                    Err(BridgeError::StreamActive)
+                } else if to_write > self.recv_buffer.len() {
+                    Err(BridgeError::BadLength)
+                } else {
+                    self.write_bits_from_buf(addr, to_write)
+                }
+            }
+
+            Request::ReadIntoBuf { addr, len } => {
+                let to_read = usize::from(len);
+
+                if to_read > self.send_buffer.free() {
+                    Err(BridgeError::BufferFull)
+                } else {
+                    self.read_into_buf(addr, to_read)
+                }
+            }
+
+            Request::ReadBitsIntoBuf { addr, bit, len } => {
+                let to_read = usize::from(len);
+
+                if to_read > self.send_buffer.free() {
+                    Err(BridgeError::BufferFull)
+                } else {
+                    self.read_bits_into_buf(addr, bit, to_read)
+                }
+            }
+
+            Request::ReadRangeIntoBuf { range, len } => {
+                let to_read = usize::from(len);
+
+                if to_read > self.send_buffer.free() {
+                    Err(BridgeError::BufferFull)
+                } else {
+                    self.read_range_into_buf(range, to_read)
+                }
+            }
+
+            Request::WriteRangeFromBuf { range, len } => {
+                let to_write = usize::from(len);
+
+                if self.stream_mode {
+                    Err(BridgeError::StreamActive)
+                } else if to_write > self.recv_buffer.len() {
+                    Err(BridgeError::BadLength)
+                } else {
+                    self.write_range_from_buf(range, to_write)
+                }
+            }
+
+            Request::ScatterGather { entries } => {
+                let entries = usize::from(entries);
+
+                if self.stream_mode {
+                    Err(BridgeError::StreamActive)
+                } else if entries * SCATTER_ENTRY_LEN > self.recv_buffer.len() {
+                    Err(BridgeError::BadLength)
+                } else {
+                    self.scatter_gather(entries)
+                }
+            }
+
+            Request::SetStreamMode { enabled } => {
+                self.stream_mode = enabled;
+                self.stream_op = None;
+                self.recv_buffer.clear();
+                Ok(())
+            }
+
+            Request::SetWatch {
+                addr,
+                mask,
+                interval_ms,
+            } => {
+                // an interval of zero stops watching
+                self.watch = (interval_ms != 0).then(|| Watch {
+                    addr,
+                    mask,
+                    interval_us: u64::from(interval_ms) * 1000,
+                    next: 0,
+                    last: None,
+                });
+                Ok(())
+            }
+
+            Request::SetBusTiming(timing) => self.set_bus_timing(timing),
+
+            Request::StartCapture(config) => self.start_capture(config),
+
+            Request::ReadCapture { first, count } => {
+                let first = usize::from(first);
+                let count = usize::from(count);
+
+                if self.capture != CaptureState::Done {
+                    Err(BridgeError::CaptureNotReady)
+                } else if first + count > self.captured() {
+                    Err(BridgeError::BadLength)
+                } else if count * CAPTURE_UNIT_SIZE > self.send_buffer.free() {
+                    Err(BridgeError::BufferFull)
+                } else {
+                    self.read_capture(first, count);
+                    Ok(())
+                }
+            }
+
+            Request::StopCapture => {
+                self.stop_capture();
+                Ok(())
+            }
+
+            Request::LoadProgram { len } => {
+                let len = usize::from(len);
+
+                if self.stream_mode {
+                    Err(BridgeError::StreamActive)
+                } else if len > self.recv_buffer.len() || len > PROGRAM_SIZE {
+                    Err(BridgeError::BadLength)
+                } else {
+                    self.recv_buffer.peek(&mut self.program[..len]);
+                    self.consume_recv(len);
+                    self.program_len = len;
+                    Ok(())
+                }
+            }
+
+            Request::RunProgram { steps } => {
+                let steps = if steps == 0 {
+                    DEFAULT_STEP_LIMIT
+                } else {
+                    steps
+                };
+
+                self.run_program(steps)
+            }
+
+            Request::StoreScript { slot, flags, name } => {
+                // the loaded program is stored, named by the data stage
+                let header = ScriptHeader::new(self.program_len, flags, name)?;
+                self.scripts.store(
+                    usize::from(slot),
+                    &header,
+                    &self.program[..self.program_len],
+                )
+            }
+
+            Request::RunScript { slot, steps } => {
+                let steps = if steps == 0 {
+                    DEFAULT_STEP_LIMIT
+                } else {
+                    steps
+                };
+
+                self.run_script(usize::from(slot), steps)
+            }
+
+            Request::EraseScript { slot } => self.scripts.erase(usize::from(slot)),
+
+            // decode_out only gives OUT requests
+            _ => Err(BridgeError::WrongDirection),
+        }
+    }
+
+    // dropped (and counted) if the host isn't keeping up
+    fn queue_event(&mut self, event: Event) {
+        if !self.event_queue.extend(&event.encode()) {
+            self.events_lost = self.events_lost.saturating_add(1);
+        }
+    }
+
+    fn poll_watch(&mut self, mut watch: Watch) {
+        watch.next = self.board.now_us() + watch.interval_us;
+
+        match self.bus_read(watch.addr) {
+            Ok(b) => {
+                let new = b & watch.mask;
+
+                if let Some(old) = watch.last
+                    && old != new
+                {
+                    self.queue_event(Event::RegisterChanged {
+                        addr: watch.addr,
+                        old,
+                        new,
+                    });
+                }
+
+                watch.last = Some(new);
+            }
+            Err(e) => self.fail(e),
+        }
+
+        self.watch = Some(watch);
+    }
+
+    fn expired(&self, deadline: u64) -> bool {
+        self.board.now_us() >= deadline
+    }
+
+    // samples held from the last capture
+    fn captured(&mut self) -> usize {
+        match self.capture {
+            CaptureState::Done => self.sampler.samples().len(),
+            _ => 0,
+        }
+    }
+
+    // replaces whatever capture was in progress
+    fn start_capture(&mut self, config: CaptureConfig) -> BridgeResult<()> {
+        self.stop_capture();
+
+        self.sampler.arm(&config)?;
+        self.capture = CaptureState::Armed;
+        Ok(())
+    }
+
+    fn stop_capture(&mut self) {
+        self.sampler.stop();
+        self.capture = CaptureState::Idle;
+    }
+
+    fn update_capture(&mut self) {
+        if !matches!(self.capture, CaptureState::Armed | CaptureState::Running) {
+            return;
+        }
+
+        self.capture = self.sampler.poll();
+
+        if self.capture == CaptureState::Done {
+            self.queue_event(Event::CaptureDone);
+        }
+    }
+
+    fn read_capture(&mut self, first: usize, count: usize) {
+        for &sample in &self.sampler.samples()[first..first + count] {
+            self.send_buffer.extend(&encode_sample(sample));
+        }
+    }
+
+    fn card_ready(&self) -> BridgeResult<()> {
+        if self.card.present() {
+            Ok(())
+        } else {
+            Err(BridgeError::NoCard)
+        }
+    }
+
+    fn bus_read(&mut self, addr: u8) -> BridgeResult<u8> {
+        self.card_ready()?;
+        self.bus.read(addr)
+    }
+
+    fn bus_write(&mut self, addr: u8, data: u8) -> BridgeResult<()> {
+        self.card_ready()?;
+        self.bus.write(addr, data)
+    }
+
+    fn bus_flush(&mut self) -> BridgeResult<()> {
+        self.bus.flush()
+    }
+
+    // polls until (read & mask) == (expected & mask), returning whether it did and the last value
+    fn wait_for(
+        &mut self,
+        addr: u8,
+        mask: u8,
+        expected: u8,
+        timeout_us: u64,
+    ) -> BridgeResult<WaitForResult> {
+        let deadline = self.board.now_us() + timeout_us;
+
+        loop {
+            let b = self.bus_read(addr)?;
+
+            if b & mask == expected & mask {
+                return Ok(WaitForResult {
+                    matched: true,
+                    value: b,
+                });
+            }
+
+            if self.expired(deadline) {
+                return Ok(WaitForResult {
+                    matched: false,
+                    value: b,
+                });
+            }
+        }
+    }
+
+    // safest with no card, as nothing is being driven
+    fn set_bus_timing(&mut self, timing: BusTiming) -> BridgeResult<()> {
+        self.bus.set_timing(&timing)?;
+
+        self.timing = timing;
+        Ok(())
+    }
+
+    fn consume_recv(&mut self, amount: usize) {
+        self.recv_buffer.consume(amount);
+
+        if amount > 0 && self.recv_buffer.is_empty() {
+            self.queue_event(Event::RecvEmpty);
+        }
+    }
+
+    // `per_byte` writes for each buffered byte, given by `write(n, b, i)` for the ith write of
+    // byte n; the bus may still be busy with the last of them when this returns
+    fn queue_writes(
+        &mut self,
+        to_write: usize,
+        per_byte: usize,
+        write: impl Fn(usize, u8, usize) -> (u8, u8),
+    ) -> BridgeResult<()> {
+        self.card_ready()?;
+
+        let recv = &self.recv_buffer;
+
+        self.bus.write_each(to_write * per_byte, |n| {
+            let i = n / per_byte;
+            write(i, recv.get(i), n % per_byte)
+        })?;
+
+        self.consume_recv(to_write);
+
+        Ok(())
+    }
+
+    fn write_from_buf(&mut self, addr: u8, to_write: usize) -> BridgeResult<()> {
+        self.queue_writes(to_write, 1, |_, b, _| (addr, b))
+    }
+
+    fn write_range_from_buf(&mut self, range: AddrRange, to_write: usize) -> BridgeResult<()> {
+        self.queue_writes(to_write, 1, |n, b, _| (range.addr(n), b))
+    }
+
+    fn write_bits_from_buf(&mut self, addr: u8, to_write: usize) -> BridgeResult<()> {
+        self.queue_writes(to_write, u8::BITS as usize, |_, b, i| (addr, (b >> i) & 1))
+    }
+
+    fn read_into_buf(&mut self, addr: u8, to_read: usize) -> BridgeResult<()> {
+        self.read_range_into_buf(AddrRange::new(addr, 0, 0), to_read)
+    }
+
+    fn read_range_into_buf(&mut self, range: AddrRange, to_read: usize) -> BridgeResult<()> {
+        if to_read > 0 {
+            self.card_ready()?;
+        }
+
+        let send = &mut self.send_buffer;
+
+        self.bus.read_each(
+            to_read,
+            |n| range.addr(n),
+            |b| {
+                send.push(b);
+            },
+        )
+    }
+
+    // the inverse of write_bits_from_buf: each byte is assembled LSB-first from eight reads
+    fn read_bits_into_buf(&mut self, addr: u8, bit: u8, to_read: usize) -> BridgeResult<()> {
+        if to_read > 0 {
+            self.card_ready()?;
+        }
+
+        let send = &mut self.send_buffer;
+        let mut b = 0;
+        let mut i = 0;
+
+        self.bus.read_each(
+            to_read * u8::BITS as usize,
+            |_| addr,
+            |s| {
+                b |= ((s >> bit) & 1) << i;
+                i += 1;
+
+                if i == u8::BITS {
+                    send.push(b);
+                    b = 0;
+                    i = 0;
+                }
+            },
+        )
+    }
+
+    // executes buffered (address, op, data) entries in order, where op is Write or Read, and
+    // queues the result of every read
+    fn scatter_gather(&mut self, entries: usize) -> BridgeResult<()> {
+        let mut reads = 0;
+
+        for n in 0..entries {
+            match ControlCommand::try_from(self.recv_buffer.get(n * SCATTER_ENTRY_LEN + 1)) {
+                Ok(ControlCommand::Write) => {}
+                Ok(ControlCommand::Read) => reads += 1,
+                _ => return Err(BridgeError::BadArgument),
+            }
+        }
+
+        if reads > self.send_buffer.free() {
+            return Err(BridgeError::BufferFull);
+        }
+
+        for n in 0..entries {
+            let entry = n * SCATTER_ENTRY_LEN;
+            let addr = self.recv_buffer.get(entry);
+
+            if self.recv_buffer.get(entry + 1) == ControlCommand::Read as u8 {
+                let b = self.bus_read(addr)?;
+                self.send_buffer.push(b);
+            } else {
+                self.bus_write(addr, self.recv_buffer.get(entry + 2))?;
+            }
+        }
+
+        self.consume_recv(entries * SCATTER_ENTRY_LEN);
+        self.bus_flush()
+    }
+
+    fn run_program(&mut self, steps: u32) -> BridgeResult<()> {
+        // a copy, so the program can't change under the VM
+        let program = self.program;
+
+        self.vm_deadline = self.board.now_us() + VM_TIMEOUT_US;
+
+        Vm::new(&program[..self.program_len]).run(self, steps)?;
+        self.bus_flush()
+    }
+
+    // leaves the script loaded, as if it had come from LoadProgram
+    fn run_script(&mut self, slot: usize, steps: u32) -> BridgeResult<()> {
+        self.program_len = self.scripts.load(slot, &mut self.program)?;
+        self.run_program(steps)
+    }
+
+    fn run_stream(&mut self) {
+        loop {
+            let Some(mut op) = self.stream_op else {
+                let mut header = [0; STREAM_HEADER_LEN];
+
+                if self.recv_buffer.peek(&mut header) < STREAM_HEADER_LEN {
+                    return;
+                }
+
+                match StreamOp::decode(&header) {
+                    Ok(op) => {
+                        self.consume_recv(STREAM_HEADER_LEN);
+                        self.stream_op = Some(op);
+                        continue;
+                    }
+                    Err(_) => {
+                        // can't resync with the host, so drop everything
+                        self.fail(BridgeError::BadStreamFrame);
+                        self.recv_buffer.clear();
+                        return;
+                    }
+                }
+            };
+
+            if op.remaining == 0 {
+                self.stream_op = None;
+                continue;
+            }
+
+            let remaining = usize::from(op.remaining);
+
+            let (amount, res) = match op.cmd {
+                ControlCommand::WriteFromBuf => {
+                    let amount = remaining.min(self.recv_buffer.len());
+                    (amount, self.write_from_buf(op.addr, amount))
+                }
+                ControlCommand::WriteBitsFromBuf => {
+                    let amount = remaining.min(self.recv_buffer.len());
+                    (amount, self.write_bits_from_buf(op.addr, amount))
+                }
+                ControlCommand::ReadIntoBuf => {
+                    let amount = remaining.min(self.send_buffer.free());
+                    (amount, self.read_into_buf(op.addr, amount))
+                }
+                ControlCommand::ReadBitsIntoBuf => {
+                    let amount = remaining.min(self.send_buffer.free());
+                    (amount, self.read_bits_into_buf(op.addr, op.bit, amount))
+                }
+                ControlCommand::ScatterGather => {
+                    let amount = remaining
+                        .min(self.recv_buffer.len() / SCATTER_ENTRY_LEN)
+                        .min(self.send_buffer.free());
+                    (amount, self.scatter_gather(amount))
+                }
+                _ => unreachable!(),
+            };
+
+            if let Err(e) = res {
+                self.fail(e);
+                self.stream_op = None;
+                self.recv_buffer.clear();
+                return;
+            }
+
+            if amount == 0 {
+                return;
+            }
+
+            op.remaining -= amount as u16;
+            self.stream_op = Some(op);
+        }
+    }
+}
+
+impl<Bus, B, S, Sc> Machine for Handler<Bus, B, S, Sc>
+where
+    Bus: CardBus,
+    B: Board,
+    S: Sampler,
+    Sc: ScriptStore,
+{
+    fn read_reg(&mut self, addr: u8) -> BridgeResult<u8> {
+        self.bus_read(addr)
+    }
+
+    fn write_reg(&mut self, addr: u8, data: u8) -> BridgeResult<()> {
+        self.bus_write(addr, data)
+    }
+
+    fn emit(&mut self, b: u8) -> BridgeResult<()> {
+        if self.send_buffer.push(b) {
+            Ok(())
+        } else {
+            Err(BridgeError::BufferFull)
+        }
+    }
+
+    fn delay_us(&mut self, us: u16) {
+        let end = self.board.now_us() + u64::from(us);
+
+        while !self.expired(end) {
+            // wait
+        }
+    }
+
+    fn timed_out(&self) -> bool {
+        self.expired(self.vm_deadline)
+    }
+}
+
+fn reply(out: &mut [u8; CONTROL_IN_LEN], data: &[u8]) -> BridgeResult<usize> {
+    out[..data.len()].copy_from_slice(data);
+    Ok(data.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::board::MockBoard;
+    use crate::bus::MockBus;
+    use crate::capture::MockSampler;
+    use crate::card::DEBOUNCE_US;
+    use crate::program::{MockScripts, SCRIPT_AUTORUN};
+    use crate::response::decode_buffer_len;
+
+    const PINS: PinMap = PinMap {
+        data_base: 0,
+        data_len: 8,
+        addr_base: 8,
+        addr_len: 8,
+        dir: 16,
+        clk: 17,
+        detect: 18,
+        sense: 19,
+    };
+
+    const SENSE: u32 = 1 << 19;
+
+    type TestHandler = Handler<MockBus, MockBoard, MockSampler, MockScripts>;
+
+    // with a card plugged in, and a clock that moves on a microsecond every time it's read
+    fn handler() -> TestHandler {
+        Handler::new(
+            MockBus::new(),
+            MockBoard::new(SENSE, 1),
+            MockSampler::new(0x00AB_CDEF),
+            MockScripts::new(),
+            PINS,
+            [1, 2, 3],
+        )
+    }
+
+    fn out(handler: &mut TestHandler, request: Request) -> BridgeResult<()> {
+        let request = request.encode();
+        handler.control_out(&request.setup, request.data())
+    }
+
+    fn get(handler: &mut TestHandler, request: Request) -> BridgeResult<[u8; CONTROL_IN_LEN]> {
+        let mut data = [0; CONTROL_IN_LEN];
+        handler.control_in(&request.encode().setup, &mut data)?;
+        Ok(data)
+    }
+
+    fn last_error(handler: &mut TestHandler) -> Option<BridgeError> {
+        let data = get(handler, Request::GetLastError).unwrap();
+        LastError::decode(&data[..LAST_ERROR_LEN])
+            .unwrap()
+            .error()
+            .map(Result::unwrap)
+    }
+
+    fn recv_len(handler: &mut TestHandler) -> u32 {
+        let data = get(handler, Request::GetRecvLen).unwrap();
+        decode_buffer_len(&data[..BUFFER_LEN_LEN]).unwrap()
+    }
+
+    // everything queued for the host, as the endpoint would take it
+    fn take(handler: &mut TestHandler, out: &mut [u8]) -> usize {
+        let n = handler.pending(out);
+        handler.sent(n);
+        n
+    }
+
+    // whether `event` is waiting for the interrupt endpoint
+    fn queued(handler: &mut TestHandler, event: Event) -> bool {
+        let mut events = [0; EVENT_QUEUE_SIZE];
+        let n = handler.pending_events(&mut events);
+        events[..n]
+            .chunks(EVENT_LEN)
+            .any(|e| Event::decode(e) == Ok(event))
+    }
+
+    fn unplug(handler: &mut TestHandler) {
+        handler.board_mut().pins &= !SENSE;
+        handler.update();
+        handler.board().advance(DEBOUNCE_US);
+        handler.update();
+    }
+
+    #[test]
+    fn info_describes_this_bridge() {
+        let mut handler = handler();
+
+        let data = get(&mut handler, Request::GetInfo).unwrap();
+        let info = Info::decode(&data[..INFO_LEN]).unwrap();
+
+        assert_eq!(info.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(info.firmware_version, [1, 2, 3]);
+        assert_eq!(usize::from(info.read_packet_size), BRIDGE_READ_SIZE);
+        assert_eq!(usize::from(info.write_packet_size), BRIDGE_WRITE_SIZE);
+        assert_eq!(info.recv_buffer_size as usize, RECV_BUFFER_SIZE);
+        assert_eq!(info.send_buffer_size as usize, SEND_BUFFER_SIZE);
+        assert_eq!(info.pins, PINS);
+        assert!(info.supports(ControlCommand::ScatterGather));
+        assert!(info.supports(ControlCommand::GetInfo));
+    }
+
+    #[test]
+    fn reads_and_writes_reach_the_card() {
+        let mut handler = handler();
+
+        out(
+            &mut handler,
+            Request::Write {
+                addr: 0x10,
+                data: 0xAB,
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            get(&mut handler, Request::Read { addr: 0x10 }).unwrap()[0],
+            0xAB
+        );
+        assert_eq!(handler.bus().registers[0x10], 0xAB);
+        assert_eq!(handler.bus().flushes, 1);
+    }
+
+    #[test]
+    fn requests_fail_without_a_card() {
+        let mut handler = handler();
+
+        unplug(&mut handler);
+
+        assert!(queued(&mut handler, Event::CardRemoved));
+        assert_eq!(
+            get(&mut handler, Request::Read { addr: 0 }),
+            Err(BridgeError::NoCard)
+        );
+        assert_eq!(last_error(&mut handler), Some(BridgeError::NoCard));
+        assert_eq!(handler.bus().reads, 0);
+    }
+
+    #[test]
+    fn bus_timing_can_be_set_without_a_card() {
+        let mut handler = handler();
+
+        unplug(&mut handler);
+
+        let timing = BusTiming {
+            clock_int: 100,
+            ..BusTiming::DEFAULT
+        };
+        out(&mut handler, Request::SetBusTiming(timing)).unwrap();
+
+        let data = get(&mut handler, Request::GetBusTiming).unwrap();
+        assert_eq!(BusTiming::decode(&data[..BUS_TIMING_LEN]), Ok(timing));
+    }
+
+    #[test]
+    fn buffered_writes_wait_for_their_data() {
+        let mut handler = handler();
+
+        handler.receive(&[1, 2, 3]);
+
+        assert_eq!(
+            out(&mut handler, Request::WriteFromBuf { addr: 0x20, len: 4 }),
+            Err(BridgeError::BadLength)
+        );
+        assert_eq!(recv_len(&mut handler), 3);
+
+        out(&mut handler, Request::WriteFromBuf { addr: 0x20, len: 3 }).unwrap();
+
+        assert_eq!(handler.bus().registers[0x20], 3);
+        assert_eq!(handler.bus().writes, 3);
+        assert_eq!(recv_len(&mut handler), 0);
+        assert!(queued(&mut handler, Event::RecvEmpty));
+    }
+
+    #[test]
+    fn bits_are_read_from_the_requested_bit() {
+        let mut handler = handler();
+        handler.bus_mut().registers[0x30] = 0b0000_0100;
+
+        let read = |handler: &mut TestHandler, bit| {
+            let request = Request::ReadBitsIntoBuf {
+                addr: 0x30,
+                bit,
+                len: 1,
+            };
+            out(handler, request).unwrap();
+
+            let mut b = [0];
+            take(handler, &mut b);
+            b[0]
+        };
+
+        assert_eq!(read(&mut handler, 2), 0xFF);
+        assert_eq!(read(&mut handler, 1), 0x00);
+    }
+
+    #[test]
+    fn ranges_read_their_addresses_in_order() {
+        let mut handler = handler();
+
+        for (addr, b) in handler.bus_mut().registers.iter_mut().enumerate() {
+            *b = !(addr as u8);
+        }
+
+        let range = AddrRange::new(0xFE, 1, 3);
+        out(&mut handler, Request::ReadRangeIntoBuf { range, len: 5 }).unwrap();
+
+        let mut data = [0; 8];
+        assert_eq!(take(&mut handler, &mut data), 5);
+        assert_eq!(data[..5], [0x01, 0x00, 0xFF, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn ranges_write_their_addresses_in_order() {
+        let mut handler = handler();
+
+        handler.receive(&[1, 2, 3, 4, 5]);
+
+        let range = AddrRange::new(0x40, 0x10, 0x20);
+        assert_eq!(
+            out(&mut handler, Request::WriteRangeFromBuf { range, len: 6 }),
+            Err(BridgeError::BadLength)
+        );
+
+        out(&mut handler, Request::WriteRangeFromBuf { range, len: 5 }).unwrap();
+
+        // 0x40 and 0x50 each written again, as the window is two strides wide
+        assert_eq!(handler.bus().registers[0x40], 5);
+        assert_eq!(handler.bus().registers[0x50], 4);
+        assert_eq!(handler.bus().writes, 5);
+        assert_eq!(recv_len(&mut handler), 0);
+    }
+
+    #[test]
+    fn watches_report_masked_changes_once_per_interval() {
+        let mut handler = handler();
+
+        // everything queued for the interrupt endpoint since last time
+        let events = |handler: &mut TestHandler| {
+            let mut events = [0; EVENT_QUEUE_SIZE];
+            let n = handler.pending_events(&mut events);
+            handler.events_sent(n);
+            n / EVENT_LEN
+        };
+
+        let watch = Request::SetWatch {
+            addr: 0x30,
+            mask: 0x0F,
+            interval_ms: 1,
+        };
+        out(&mut handler, watch).unwrap();
+
+        // the first sample only sets the baseline
+        handler.bus_mut().registers[0x30] = 0x01;
+        handler.update();
+        assert_eq!(handler.bus().reads, 1);
+        assert_eq!(events(&mut handler), 0);
+
+        // not due again yet
+        handler.bus_mut().registers[0x30] = 0x02;
+        handler.update();
+        assert_eq!(handler.bus().reads, 1);
+
+        handler.board().advance(1000);
+        handler.update();
+        assert_eq!(handler.bus().reads, 2);
+        assert!(queued(
+            &mut handler,
+            Event::RegisterChanged {
+                addr: 0x30,
+                old: 0x01,
+                new: 0x02
+            }
+        ));
+        assert_eq!(events(&mut handler), 1);
+
+        // outside the mask
+        handler.bus_mut().registers[0x30] = 0xF2;
+        handler.board().advance(1000);
+        handler.update();
+        assert_eq!(handler.bus().reads, 3);
+        assert_eq!(events(&mut handler), 0);
+
+        // an interval of zero stops it
+        let stop = Request::SetWatch {
+            addr: 0x30,
+            mask: 0x0F,
+            interval_ms: 0,
+        };
+        out(&mut handler, stop).unwrap();
+        handler.board().advance(1000);
+        handler.update();
+        assert_eq!(handler.bus().reads, 3);
+    }
+
+    #[test]
+    fn reads_only_queue_what_fits() {
+        let mut handler = handler();
+
+        assert_eq!(
+            out(
+                &mut handler,
+                Request::ReadIntoBuf {
+                    addr: 0,
+                    len: u16::MAX
+                }
+            ),
+            Err(BridgeError::BufferFull)
+        );
+        assert_eq!(handler.bus().reads, 0);
+    }
+
+    #[test]
+    fn scatter_gather_runs_in_order() {
+        let mut handler = handler();
+
+        let read = ControlCommand::Read as u8;
+        let write = ControlCommand::Write as u8;
+
+        handler.receive(&[0x40, write, 0x12, 0x40, read, 0, 0x41, write, 0x34]);
+        out(&mut handler, Request::ScatterGather { entries: 3 }).unwrap();
+
+        let mut data = [0; 2];
+        assert_eq!(take(&mut handler, &mut data), 1);
+        assert_eq!(data[0], 0x12);
+        assert_eq!(handler.bus().registers[0x41], 0x34);
+
+        // nothing is done unless every entry is good
+        handler.receive(&[0x50, write, 0x56, 0x50, 0xEE, 0]);
+        assert_eq!(
+            out(&mut handler, Request::ScatterGather { entries: 2 }),
+            Err(BridgeError::BadArgument)
+        );
+        assert_eq!(handler.bus().registers[0x50], 0);
+        assert_eq!(recv_len(&mut handler), 6);
+    }
+
+    #[test]
+    fn stream_mode_runs_ops_as_their_data_arrives() {
+        let mut handler = handler();
+
+        out(&mut handler, Request::SetStreamMode { enabled: true }).unwrap();
+
+        let header = StreamOp {
+            cmd: ControlCommand::WriteFromBuf,
+            addr: 0x60,
+            bit: 0,
+            remaining: 2,
+        };
+        handler.receive(&header.encode());
+        handler.receive(&[7]);
+        assert_eq!(handler.bus().registers[0x60], 7);
+        handler.receive(&[8]);
+        assert_eq!(handler.bus().registers[0x60], 8);
+
+        assert_eq!(
+            out(&mut handler, Request::WriteFromBuf { addr: 0x60, len: 0 }),
+            Err(BridgeError::StreamActive)
+        );
+
+        // can't be resynchronised, so everything is dropped
+        handler.receive(&[0xEE, 0, 0, 0, 1, 9]);
+        assert_eq!(last_error(&mut handler), Some(BridgeError::BadStreamFrame));
+        assert_eq!(recv_len(&mut handler), 0);
+    }
+
+    #[test]
+    fn wait_for_gives_up_at_its_timeout() {
+        let mut handler = handler();
+        handler.bus_mut().registers[0x70] = 0x81;
+
+        let wait = |handler: &mut TestHandler, expected| {
+            let request = Request::WaitFor {
+                addr: 0x70,
+                mask: 0x80,
+                expected,
+                timeout: 2,
+            };
+            let data = get(handler, request).unwrap();
+            WaitForResult::decode(&data[..WAIT_FOR_LEN]).unwrap()
+        };
+
+        assert_eq!(
+            wait(&mut handler, 0x80),
+            WaitForResult {
+                matched: true,
+                value: 0x81
+            }
+        );
+        assert_eq!(handler.bus().reads, 1);
+
+        let start = handler.board().now.get();
+
+        assert_eq!(
+            wait(&mut handler, 0x00),
+            WaitForResult {
+                matched: false,
+                value: 0x81
+            }
+        );
+        assert!(handler.board().now.get() - start >= 2 * WAIT_FOR_UNIT_US);
+    }
+
+    #[test]
+    fn programs_emit_for_the_host() {
+        let mut handler = handler();
+        handler.bus_mut().registers[0x01] = 0x5A;
+
+        // Read 0x01, WriteA 0x02, Emit
+        handler.receive(&[0x01, 0x01, 0x03, 0x02, 0x31]);
+        out(&mut handler, Request::LoadProgram { len: 5 }).unwrap();
+        out(&mut handler, Request::RunProgram { steps: 0 }).unwrap();
+
+        let mut data = [0; 2];
+        assert_eq!(take(&mut handler, &mut data), 1);
+        assert_eq!(data[0], 0x5A);
+        assert_eq!(handler.bus().registers[0x02], 0x5A);
+    }
+
+    #[test]
+    fn scripts_are_stored_run_and_erased() {
+        let mut handler = handler();
+        handler.bus_mut().registers[0x01] = 0x5A;
+
+        // Read 0x01, Emit
+        handler.receive(&[0x01, 0x01, 0x31]);
+        out(&mut handler, Request::LoadProgram { len: 3 }).unwrap();
+
+        let store = Request::StoreScript {
+            slot: 2,
+            flags: SCRIPT_AUTORUN,
+            name: b"peek",
+        };
+        out(&mut handler, store).unwrap();
+
+        let data = get(&mut handler, Request::GetScriptInfo { slot: 2 }).unwrap();
+        let header = ScriptHeader::decode(&data[..SCRIPT_HEADER_LEN]).unwrap();
+        assert_eq!(header.name(), b"peek");
+        assert_eq!(header.len, 3);
+
+        handler.run_boot_script();
+        out(&mut handler, Request::RunScript { slot: 2, steps: 0 }).unwrap();
+
+        let mut emitted = [0; 4];
+        assert_eq!(take(&mut handler, &mut emitted), 2);
+        assert_eq!(emitted[..2], [0x5A, 0x5A]);
+
+        out(&mut handler, Request::EraseScript { slot: 2 }).unwrap();
+        assert_eq!(
+            get(&mut handler, Request::GetScriptInfo { slot: 2 }),
+            Err(BridgeError::NoScript)
+        );
+        assert_eq!(
+            out(&mut handler, Request::RunScript { slot: 2, steps: 0 }),
+            Err(BridgeError::NoScript)
+        );
+    }
+
+    #[test]
+    fn captures_wait_for_their_trigger() {
+        let mut handler = handler();
+
+        let config = CaptureConfig {
+            clock_int: 1,
+            clock_frac: 0,
+            samples: 4,
+            trigger_mask: 1 << 24,
+            trigger_value: 1 << 24,
+        };
+        out(&mut handler, Request::StartCapture(config)).unwrap();
+
+        let state = |handler: &mut TestHandler| {
+            let data = get(handler, Request::GetCaptureStatus).unwrap();
+            CaptureStatus::decode(&data[..CAPTURE_STATUS_LEN]).unwrap()
+        };
+
+        handler.update();
+        assert_eq!(state(&mut handler).state, CaptureState::Armed);
+        assert_eq!(
+            out(&mut handler, Request::ReadCapture { first: 0, count: 1 }),
+            Err(BridgeError::CaptureNotReady)
+        );
+
+        handler.sampler_mut().level |= 1 << 24;
+        handler.update();
+        assert_eq!(state(&mut handler).state, CaptureState::Running);
+
+        handler.update();
+        assert_eq!(
+            state(&mut handler),
+            CaptureStatus {
+                state: CaptureState::Done,
+                samples: 4
+            }
+        );
+        assert!(queued(&mut handler, Event::CaptureDone));
+
+        assert_eq!(
+            out(&mut handler, Request::ReadCapture { first: 2, count: 3 }),
+            Err(BridgeError::BadLength)
+        );
+        out(&mut handler, Request::ReadCapture { first: 2, count: 2 }).unwrap();
+
+        let mut data = [0; 8];
+        assert_eq!(take(&mut handler, &mut data), 2 * CAPTURE_UNIT_SIZE);
+        assert_eq!(data[..6], [0xEF, 0xCD, 0xAB, 0xEF, 0xCD, 0xAB]);
+    }
+
+    #[test]
+    fn the_host_is_held_off_once_the_receive_buffer_fills() {
+        let mut handler = handler();
+
+        let mut packets = 0;
+
+        while handler.can_receive() {
+            handler.receive(&[0; BRIDGE_READ_SIZE]);
+            packets += 1;
+        }
+
+        assert_eq!(packets, RECV_BUFFER_SIZE / BRIDGE_READ_SIZE);
+        assert!(!handler.can_receive());
+
+        let mut events = [0; EVENT_QUEUE_SIZE];
+        let n = handler.pending_events(&mut events);
+        assert_eq!(events[..n], Event::RecvFull.encode());
+    }
+}
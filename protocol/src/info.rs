@@ -0,0 +1,231 @@
+use crate::command::ControlCommand;
+use crate::error::{BridgeError, BridgeResult};
+
+// bumped whenever a request or response changes incompatibly
+pub const PROTOCOL_VERSION: u8 = 3;
+
+// protocol, firmware version, packet sizes, buffer sizes, pins, command bitmap
+pub const INFO_LEN: usize = 1 + 3 + 2 * 2 + 2 * 4 + PIN_MAP_LEN + COMMAND_BITMAP_LEN;
+
+const PIN_MAP_LEN: usize = 8;
+const COMMAND_BITMAP_LEN: usize = 256 / 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PinMap {
+    pub data_base: u8,
+    pub data_len: u8,
+    pub addr_base: u8,
+    pub addr_len: u8,
+    pub dir: u8,
+    pub clk: u8,
+    pub detect: u8,
+    pub sense: u8,
+}
+
+impl PinMap {
+    // one bit per GPIO
+    pub fn data_mask(&self) -> u32 {
+        Self::mask(self.data_base, self.data_len)
+    }
+
+    pub fn addr_mask(&self) -> u32 {
+        Self::mask(self.addr_base, self.addr_len)
+    }
+
+    pub fn ctrl_mask(&self) -> u32 {
+        (1 << self.dir) | (1 << self.clk)
+    }
+
+    fn mask(base: u8, len: u8) -> u32 {
+        ((1 << len) - 1) << base
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Info {
+    pub protocol_version: u8,
+    // major, minor, patch
+    pub firmware_version: [u8; 3],
+    pub read_packet_size: u16,
+    pub write_packet_size: u16,
+    pub recv_buffer_size: u32,
+    pub send_buffer_size: u32,
+    pub pins: PinMap,
+    // one bit per bRequest
+    pub commands: [u8; COMMAND_BITMAP_LEN],
+}
+
+impl Info {
+    pub fn decode(data: &[u8]) -> BridgeResult<Self> {
+        if data.len() != INFO_LEN {
+            return Err(BridgeError::BadLength);
+        }
+
+        let mut pos = 0;
+
+        let mut take = |len: usize| {
+            let field = &data[pos..pos + len];
+            pos += len;
+            field
+        };
+
+        let protocol_version = take(1)[0];
+        let firmware = take(3);
+        let read_packet_size = take(2);
+        let write_packet_size = take(2);
+        let recv_buffer_size = take(4);
+        let send_buffer_size = take(4);
+        let pins = take(PIN_MAP_LEN);
+
+        let mut commands = [0; COMMAND_BITMAP_LEN];
+        commands.copy_from_slice(take(COMMAND_BITMAP_LEN));
+
+        Ok(Self {
+            protocol_version,
+            firmware_version: [firmware[0], firmware[1], firmware[2]],
+            read_packet_size: u16::from_be_bytes([read_packet_size[0], read_packet_size[1]]),
+            write_packet_size: u16::from_be_bytes([write_packet_size[0], write_packet_size[1]]),
+            recv_buffer_size: u32::from_be_bytes([
+                recv_buffer_size[0],
+                recv_buffer_size[1],
+                recv_buffer_size[2],
+                recv_buffer_size[3],
+            ]),
+            send_buffer_size: u32::from_be_bytes([
+                send_buffer_size[0],
+                send_buffer_size[1],
+                send_buffer_size[2],
+                send_buffer_size[3],
+            ]),
+            pins: PinMap {
+                data_base: pins[0],
+                data_len: pins[1],
+                addr_base: pins[2],
+                addr_len: pins[3],
+                dir: pins[4],
+                clk: pins[5],
+                detect: pins[6],
+                sense: pins[7],
+            },
+            commands,
+        })
+    }
+
+    pub fn encode(&self) -> [u8; INFO_LEN] {
+        let mut buf = [0; INFO_LEN];
+        let mut pos = 0;
+
+        let mut put = |data: &[u8]| {
+            buf[pos..pos + data.len()].copy_from_slice(data);
+            pos += data.len();
+        };
+
+        put(&[self.protocol_version]);
+        put(&self.firmware_version);
+        put(&self.read_packet_size.to_be_bytes());
+        put(&self.write_packet_size.to_be_bytes());
+        put(&self.recv_buffer_size.to_be_bytes());
+        put(&self.send_buffer_size.to_be_bytes());
+        put(&[
+            self.pins.data_base,
+            self.pins.data_len,
+            self.pins.addr_base,
+            self.pins.addr_len,
+            self.pins.dir,
+            self.pins.clk,
+            self.pins.detect,
+            self.pins.sense,
+        ]);
+        put(&self.commands);
+
+        buf
+    }
+
+    pub fn supports(&self, cmd: ControlCommand) -> bool {
+        let cmd = cmd as u8;
+        self.commands[usize::from(cmd / 8)] & (1 << (cmd % 8)) != 0
+    }
+}
+
+// every command this version of the protocol defines
+pub fn command_bitmap() -> [u8; COMMAND_BITMAP_LEN] {
+    let mut bitmap = [0; COMMAND_BITMAP_LEN];
+
+    for cmd in 0..=u8::MAX {
+        if ControlCommand::try_from(cmd).is_ok() {
+            bitmap[usize::from(cmd / 8)] |= 1 << (cmd % 8);
+        }
+    }
+
+    bitmap
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PINS: PinMap = PinMap {
+        data_base: 0,
+        data_len: 8,
+        addr_base: 8,
+        addr_len: 8,
+        dir: 16,
+        clk: 17,
+        detect: 18,
+        sense: 19,
+    };
+
+    #[test]
+    fn info_round_trips() {
+        let info = Info {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: [1, 2, 3],
+            read_packet_size: 64,
+            write_packet_size: 512,
+            recv_buffer_size: 0x0001_0203,
+            send_buffer_size: 0x0405_0607,
+            pins: PINS,
+            commands: command_bitmap(),
+        };
+
+        let encoded = info.encode();
+
+        assert_eq!(encoded[..4], [PROTOCOL_VERSION, 1, 2, 3]);
+        assert_eq!(encoded[6..8], [0x02, 0x00]);
+        assert_eq!(Info::decode(&encoded), Ok(info));
+        assert_eq!(
+            Info::decode(&encoded[..INFO_LEN - 1]),
+            Err(BridgeError::BadLength)
+        );
+    }
+
+    #[test]
+    fn the_bitmap_covers_exactly_the_defined_commands() {
+        let info = Info {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: [0; 3],
+            read_packet_size: 0,
+            write_packet_size: 0,
+            recv_buffer_size: 0,
+            send_buffer_size: 0,
+            pins: PINS,
+            commands: command_bitmap(),
+        };
+
+        for cmd in 0..=u8::MAX {
+            let set = info.commands[usize::from(cmd / 8)] & (1 << (cmd % 8)) != 0;
+
+            match ControlCommand::try_from(cmd) {
+                Ok(command) => assert!(set && info.supports(command)),
+                Err(_) => assert!(!set),
+            }
+        }
+    }
+
+    #[test]
+    fn masks_cover_each_group() {
+        assert_eq!(PINS.data_mask(), 0x0000_00FF);
+        assert_eq!(PINS.addr_mask(), 0x0000_FF00);
+        assert_eq!(PINS.ctrl_mask(), 0x0003_0000);
+    }
+}
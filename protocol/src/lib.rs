@@ -0,0 +1,24 @@
+#![no_std]
+
+pub use command::{ControlCommand, Setup, pack, unpack};
+pub use error::{BridgeError, BridgeResult};
+pub use request::{EncodedRequest, Request};
+
+pub mod board;
+pub mod bus;
+pub mod capture;
+pub mod card;
+pub mod command;
+pub mod error;
+pub mod event;
+pub mod handler;
+pub mod info;
+pub mod program;
+pub mod range;
+pub mod request;
+pub mod response;
+pub mod ring;
+pub mod selftest;
+pub mod stream;
+pub mod timing;
+pub mod vm;
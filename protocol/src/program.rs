@@ -0,0 +1,165 @@
+use crate::error::{BridgeError, BridgeResult};
+
+// bytecode programs, whether loaded or stored as scripts
+pub const PROGRAM_SIZE: usize = 1024;
+pub const DEFAULT_STEP_LIMIT: u32 = 1_000_000;
+
+// stored scripts, numbered from zero
+pub const SCRIPT_SLOTS: usize = 16;
+
+pub const SCRIPT_NAME_LEN: usize = 16;
+
+// magic, program length (big-endian), flags, name
+pub const SCRIPT_HEADER_LEN: usize = 4 + 2 + 1 + SCRIPT_NAME_LEN;
+
+// run once at power-up
+pub const SCRIPT_AUTORUN: u8 = 1 << 0;
+
+const SCRIPT_MAGIC: [u8; 4] = *b"SCRP";
+
+// stored in flash ahead of each script, and returned by GetScriptInfo
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScriptHeader {
+    pub len: u16,
+    pub flags: u8,
+    // zero-padded
+    pub name: [u8; SCRIPT_NAME_LEN],
+}
+
+impl ScriptHeader {
+    // for a program of `len` bytes, which has to fit in a slot
+    pub fn new(len: usize, flags: u8, name: &[u8]) -> BridgeResult<Self> {
+        if len == 0 || len > PROGRAM_SIZE || name.len() > SCRIPT_NAME_LEN {
+            return Err(BridgeError::BadLength);
+        }
+
+        let mut header = Self {
+            len: len as u16,
+            flags,
+            name: [0; SCRIPT_NAME_LEN],
+        };
+        header.name[..name.len()].copy_from_slice(name);
+
+        Ok(header)
+    }
+
+    // erased flash reads as all ones, so an empty slot has no magic
+    pub fn decode(data: &[u8]) -> BridgeResult<Self> {
+        if data.len() != SCRIPT_HEADER_LEN {
+            return Err(BridgeError::BadLength);
+        }
+
+        if data[..4] != SCRIPT_MAGIC {
+            return Err(BridgeError::NoScript);
+        }
+
+        let len = u16::from_be_bytes([data[4], data[5]]);
+        if usize::from(len) > PROGRAM_SIZE {
+            return Err(BridgeError::NoScript);
+        }
+
+        let mut name = [0; SCRIPT_NAME_LEN];
+        name.copy_from_slice(&data[7..SCRIPT_HEADER_LEN]);
+
+        Ok(Self {
+            len,
+            flags: data[6],
+            name,
+        })
+    }
+
+    pub fn encode(&self) -> [u8; SCRIPT_HEADER_LEN] {
+        let mut buf = [0; SCRIPT_HEADER_LEN];
+
+        buf[..4].copy_from_slice(&SCRIPT_MAGIC);
+        buf[4..6].copy_from_slice(&self.len.to_be_bytes());
+        buf[6] = self.flags;
+        buf[7..].copy_from_slice(&self.name);
+
+        buf
+    }
+
+    // without the padding
+    pub fn name(&self) -> &[u8] {
+        let len = self
+            .name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(SCRIPT_NAME_LEN);
+        &self.name[..len]
+    }
+}
+
+// wherever scripts are kept between power cycles; a slot past SCRIPT_SLOTS is BadArgument, and
+// one with nothing in it NoScript
+pub trait ScriptStore {
+    fn header(&self, slot: usize) -> BridgeResult<ScriptHeader>;
+    // copies the program into `out`, returning its length
+    fn load(&self, slot: usize, out: &mut [u8; PROGRAM_SIZE]) -> BridgeResult<usize>;
+    // `program` is header.len bytes long
+    fn store(&mut self, slot: usize, header: &ScriptHeader, program: &[u8]) -> BridgeResult<()>;
+    fn erase(&mut self, slot: usize) -> BridgeResult<()>;
+
+    // the first script to run at power-up
+    fn autorun(&self) -> Option<usize> {
+        (0..SCRIPT_SLOTS).find(|&slot| {
+            self.header(slot)
+                .is_ok_and(|h| h.flags & SCRIPT_AUTORUN != 0)
+        })
+    }
+}
+
+// scripts held in memory, for tests
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MockScripts {
+    slots: [Option<(ScriptHeader, [u8; PROGRAM_SIZE])>; SCRIPT_SLOTS],
+}
+
+impl MockScripts {
+    pub const fn new() -> Self {
+        Self {
+            slots: [None; SCRIPT_SLOTS],
+        }
+    }
+
+    fn slot(&self, slot: usize) -> BridgeResult<&(ScriptHeader, [u8; PROGRAM_SIZE])> {
+        self.slots
+            .get(slot)
+            .ok_or(BridgeError::BadArgument)?
+            .as_ref()
+            .ok_or(BridgeError::NoScript)
+    }
+}
+
+impl Default for MockScripts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ScriptStore for MockScripts {
+    fn header(&self, slot: usize) -> BridgeResult<ScriptHeader> {
+        self.slot(slot).map(|(header, _)| *header)
+    }
+
+    fn load(&self, slot: usize, out: &mut [u8; PROGRAM_SIZE]) -> BridgeResult<usize> {
+        let (header, program) = self.slot(slot)?;
+        *out = *program;
+        Ok(usize::from(header.len))
+    }
+
+    fn store(&mut self, slot: usize, header: &ScriptHeader, program: &[u8]) -> BridgeResult<()> {
+        let slot = self.slots.get_mut(slot).ok_or(BridgeError::BadArgument)?;
+
+        let mut stored = [0; PROGRAM_SIZE];
+        stored[..program.len()].copy_from_slice(program);
+        *slot = Some((*header, stored));
+
+        Ok(())
+    }
+
+    fn erase(&mut self, slot: usize) -> BridgeResult<()> {
+        *self.slots.get_mut(slot).ok_or(BridgeError::BadArgument)? = None;
+        Ok(())
+    }
+}
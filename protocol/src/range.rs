@@ -0,0 +1,60 @@
+// addresses start, start + stride, start + 2 * stride, ... taken modulo `wrap`, so a range
+// never leaves the `wrap`-sized window beginning at `start`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddrRange {
+    pub start: u8,
+    pub stride: u8,
+    pub wrap: u16,
+}
+
+impl AddrRange {
+    // a wrap of zero means the whole 256-address space
+    pub fn new(start: u8, stride: u8, wrap: u8) -> Self {
+        Self {
+            start,
+            stride,
+            wrap: if wrap == 0 { 256 } else { u16::from(wrap) },
+        }
+    }
+
+    pub fn addr(&self, index: usize) -> u8 {
+        let offset = (index * usize::from(self.stride)) % usize::from(self.wrap);
+        self.start.wrapping_add(offset as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs<const N: usize>(range: AddrRange) -> [u8; N] {
+        core::array::from_fn(|n| range.addr(n))
+    }
+
+    #[test]
+    fn strides_step_through_the_window() {
+        assert_eq!(
+            addrs(AddrRange::new(0x10, 3, 8)),
+            [0x10, 0x13, 0x16, 0x11, 0x14]
+        );
+        assert_eq!(addrs(AddrRange::new(0x20, 0, 8)), [0x20; 4]);
+    }
+
+    #[test]
+    fn a_zero_wrap_covers_every_address() {
+        let range = AddrRange::new(0xFE, 1, 0);
+
+        assert_eq!(range.wrap, 256);
+        assert_eq!(addrs(range), [0xFE, 0xFF, 0x00, 0x01]);
+        assert_eq!(range.addr(256), 0xFE);
+    }
+
+    #[test]
+    fn windows_can_cross_0xff() {
+        assert_eq!(
+            addrs(AddrRange::new(0xFD, 1, 4)),
+            [0xFD, 0xFE, 0xFF, 0x00, 0xFD, 0xFE]
+        );
+        assert_eq!(addrs(AddrRange::new(0xF0, 0x20, 0)), [0xF0, 0x10, 0x30]);
+    }
+}
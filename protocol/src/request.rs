@@ -0,0 +1,587 @@
+use crate::capture::{CAPTURE_CONFIG_LEN, CaptureConfig};
+use crate::command::{ControlCommand, Setup, pack, unpack};
+use crate::error::{BridgeError, BridgeResult};
+use crate::program::SCRIPT_NAME_LEN;
+use crate::range::AddrRange;
+use crate::timing::{BUS_TIMING_LEN, BusTiming};
+
+// WaitFor timeouts are given in units of this; the bridge services nothing else while it waits,
+// so even the longest (255 ms) has to finish well inside the host's control transfer timeout
+pub const WAIT_FOR_UNIT_US: u64 = 1_000;
+
+// address, op (Write or Read), data
+pub const SCATTER_ENTRY_LEN: usize = 3;
+
+// the longest OUT data stage
+pub const MAX_DATA_LEN: usize = {
+    let mut len = SCRIPT_NAME_LEN;
+    if BUS_TIMING_LEN > len {
+        len = BUS_TIMING_LEN;
+    }
+    if CAPTURE_CONFIG_LEN > len {
+        len = CAPTURE_CONFIG_LEN;
+    }
+    len
+};
+
+// every vendor request with its arguments unpacked from wValue, wIndex and the data stage; the
+// lengths of buffered requests are in bytes, except where noted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request<'a> {
+    Write {
+        addr: u8,
+        data: u8,
+    },
+    Read {
+        addr: u8,
+    },
+    // polls until (read & mask) == (expected & mask), for up to `timeout` WAIT_FOR_UNIT_US
+    WaitFor {
+        addr: u8,
+        mask: u8,
+        expected: u8,
+        timeout: u8,
+    },
+
+    WriteFromBuf {
+        addr: u8,
+        len: u16,
+    },
+    ReadIntoBuf {
+        addr: u8,
+        len: u16,
+    },
+    WriteBitsFromBuf {
+        addr: u8,
+        len: u16,
+    },
+    ReadBitsIntoBuf {
+        addr: u8,
+        bit: u8,
+        len: u16,
+    },
+    ReadRangeIntoBuf {
+        range: AddrRange,
+        len: u16,
+    },
+    WriteRangeFromBuf {
+        range: AddrRange,
+        len: u16,
+    },
+    ScatterGather {
+        entries: u16,
+    },
+
+    SetStreamMode {
+        enabled: bool,
+    },
+    // an interval of zero stops watching
+    SetWatch {
+        addr: u8,
+        mask: u8,
+        interval_ms: u16,
+    },
+    SetBusTiming(BusTiming),
+
+    LoadProgram {
+        len: u16,
+    },
+    // zero steps for DEFAULT_STEP_LIMIT
+    RunProgram {
+        steps: u32,
+    },
+    // stores the loaded program
+    StoreScript {
+        slot: u8,
+        flags: u8,
+        name: &'a [u8],
+    },
+    // the slot goes in a one-byte data stage, leaving the setup packet for the step limit
+    RunScript {
+        slot: u8,
+        steps: u32,
+    },
+    EraseScript {
+        slot: u16,
+    },
+
+    SelfTest,
+
+    StartCapture(CaptureConfig),
+    // counted in samples
+    ReadCapture {
+        first: u16,
+        count: u16,
+    },
+    StopCapture,
+
+    GetRecvLen,
+    GetSendLen,
+    GetLastError,
+    GetInfo,
+    GetScriptInfo {
+        slot: u16,
+    },
+    GetBusTiming,
+    GetCardStatus,
+    GetCaptureStatus,
+
+    RebootToUSB,
+}
+
+// a request ready to send: the setup packet, and for OUT requests the data stage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EncodedRequest {
+    pub setup: Setup,
+    data: [u8; MAX_DATA_LEN],
+    len: usize,
+}
+
+impl EncodedRequest {
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl<'a> Request<'a> {
+    pub fn command(&self) -> ControlCommand {
+        match self {
+            Self::Write { .. } => ControlCommand::Write,
+            Self::Read { .. } => ControlCommand::Read,
+            Self::WaitFor { .. } => ControlCommand::WaitFor,
+
+            Self::WriteFromBuf { .. } => ControlCommand::WriteFromBuf,
+            Self::ReadIntoBuf { .. } => ControlCommand::ReadIntoBuf,
+            Self::WriteBitsFromBuf { .. } => ControlCommand::WriteBitsFromBuf,
+            Self::ReadBitsIntoBuf { .. } => ControlCommand::ReadBitsIntoBuf,
+            Self::ReadRangeIntoBuf { .. } => ControlCommand::ReadRangeIntoBuf,
+            Self::WriteRangeFromBuf { .. } => ControlCommand::WriteRangeFromBuf,
+            Self::ScatterGather { .. } => ControlCommand::ScatterGather,
+
+            Self::SetStreamMode { .. } => ControlCommand::SetStreamMode,
+            Self::SetWatch { .. } => ControlCommand::SetWatch,
+            Self::SetBusTiming(_) => ControlCommand::SetBusTiming,
+
+            Self::LoadProgram { .. } => ControlCommand::LoadProgram,
+            Self::RunProgram { .. } => ControlCommand::RunProgram,
+            Self::StoreScript { .. } => ControlCommand::StoreScript,
+            Self::RunScript { .. } => ControlCommand::RunScript,
+            Self::EraseScript { .. } => ControlCommand::EraseScript,
+
+            Self::SelfTest => ControlCommand::SelfTest,
+
+            Self::StartCapture(_) => ControlCommand::StartCapture,
+            Self::ReadCapture { .. } => ControlCommand::ReadCapture,
+            Self::StopCapture => ControlCommand::StopCapture,
+
+            Self::GetRecvLen => ControlCommand::GetRecvLen,
+            Self::GetSendLen => ControlCommand::GetSendLen,
+            Self::GetLastError => ControlCommand::GetLastError,
+            Self::GetInfo => ControlCommand::GetInfo,
+            Self::GetScriptInfo { .. } => ControlCommand::GetScriptInfo,
+            Self::GetBusTiming => ControlCommand::GetBusTiming,
+            Self::GetCardStatus => ControlCommand::GetCardStatus,
+            Self::GetCaptureStatus => ControlCommand::GetCaptureStatus,
+
+            Self::RebootToUSB => ControlCommand::RebootToUSB,
+        }
+    }
+
+    // a name longer than SCRIPT_NAME_LEN is cut short
+    pub fn encode(&self) -> EncodedRequest {
+        let mut data = [0; MAX_DATA_LEN];
+        let mut len = 0;
+
+        let mut put = |bytes: &[u8]| {
+            let n = bytes.len().min(MAX_DATA_LEN);
+            data[..n].copy_from_slice(&bytes[..n]);
+            len = n;
+        };
+
+        let (value, index) = match *self {
+            Self::Write { addr, data } => (pack(addr, data), 0),
+            Self::Read { addr } => (pack(addr, 0), 0),
+            Self::WaitFor {
+                addr,
+                mask,
+                expected,
+                timeout,
+            } => (pack(addr, expected), pack(mask, timeout)),
+
+            Self::WriteFromBuf { addr, len }
+            | Self::ReadIntoBuf { addr, len }
+            | Self::WriteBitsFromBuf { addr, len } => (pack(addr, 0), len),
+            Self::ReadBitsIntoBuf { addr, bit, len } => (pack(addr, bit), len),
+            Self::ReadRangeIntoBuf { range, len } | Self::WriteRangeFromBuf { range, len } => {
+                // a wrap of 256 goes out as zero
+                put(&[range.wrap as u8]);
+                (pack(range.start, range.stride), len)
+            }
+            Self::ScatterGather { entries } => (0, entries),
+
+            Self::SetStreamMode { enabled } => (enabled as u16, 0),
+            Self::SetWatch {
+                addr,
+                mask,
+                interval_ms,
+            } => (pack(addr, mask), interval_ms),
+            Self::SetBusTiming(timing) => {
+                put(&timing.encode());
+                (0, 0)
+            }
+
+            Self::LoadProgram { len } => (0, len),
+            Self::RunProgram { steps } => ((steps >> 16) as u16, steps as u16),
+            Self::StoreScript { slot, flags, name } => {
+                put(&name[..name.len().min(SCRIPT_NAME_LEN)]);
+                (pack(flags, slot), 0)
+            }
+            Self::RunScript { slot, steps } => {
+                put(&[slot]);
+                ((steps >> 16) as u16, steps as u16)
+            }
+            Self::EraseScript { slot } => (slot, 0),
+
+            Self::SelfTest => (0, 0),
+
+            Self::StartCapture(config) => {
+                put(&config.encode());
+                (0, 0)
+            }
+            Self::ReadCapture { first, count } => (first, count),
+            Self::StopCapture => (0, 0),
+
+            Self::GetScriptInfo { slot } => (slot, 0),
+
+            Self::GetRecvLen
+            | Self::GetSendLen
+            | Self::GetLastError
+            | Self::GetInfo
+            | Self::GetBusTiming
+            | Self::GetCardStatus
+            | Self::GetCaptureStatus
+            | Self::RebootToUSB => (0, 0),
+        };
+
+        EncodedRequest {
+            setup: Setup {
+                request: self.command() as u8,
+                value,
+                index,
+            },
+            data,
+            len,
+        }
+    }
+
+    pub fn decode_in(setup: &Setup) -> BridgeResult<Self> {
+        Self::decode(setup, &[], true)
+    }
+
+    pub fn decode_out(setup: &Setup, data: &'a [u8]) -> BridgeResult<Self> {
+        Self::decode(setup, data, false)
+    }
+
+    fn decode(setup: &Setup, data: &'a [u8], is_in: bool) -> BridgeResult<Self> {
+        let cmd =
+            ControlCommand::try_from(setup.request).map_err(|_| BridgeError::UnknownCommand)?;
+
+        if cmd.is_in() != is_in {
+            return Err(BridgeError::WrongDirection);
+        }
+
+        let (hi, lo) = unpack(setup.value);
+        let index = setup.index;
+
+        let request = match cmd {
+            ControlCommand::Write => Self::Write { addr: hi, data: lo },
+            ControlCommand::Read => Self::Read { addr: hi },
+            ControlCommand::WaitFor => {
+                let (mask, timeout) = unpack(index);
+
+                Self::WaitFor {
+                    addr: hi,
+                    mask,
+                    expected: lo,
+                    timeout,
+                }
+            }
+
+            ControlCommand::WriteFromBuf => Self::WriteFromBuf {
+                addr: hi,
+                len: index,
+            },
+            ControlCommand::ReadIntoBuf => Self::ReadIntoBuf {
+                addr: hi,
+                len: index,
+            },
+            ControlCommand::WriteBitsFromBuf => Self::WriteBitsFromBuf {
+                addr: hi,
+                len: index,
+            },
+            ControlCommand::ReadBitsIntoBuf => {
+                if lo >= u8::BITS as u8 {
+                    return Err(BridgeError::BadArgument);
+                }
+
+                Self::ReadBitsIntoBuf {
+                    addr: hi,
+                    bit: lo,
+                    len: index,
+                }
+            }
+            // the wrap is in an optional data stage
+            ControlCommand::ReadRangeIntoBuf => Self::ReadRangeIntoBuf {
+                range: AddrRange::new(hi, lo, data.first().copied().unwrap_or(0)),
+                len: index,
+            },
+            ControlCommand::WriteRangeFromBuf => Self::WriteRangeFromBuf {
+                range: AddrRange::new(hi, lo, data.first().copied().unwrap_or(0)),
+                len: index,
+            },
+            ControlCommand::ScatterGather => Self::ScatterGather { entries: index },
+
+            ControlCommand::SetStreamMode => Self::SetStreamMode {
+                enabled: setup.value != 0,
+            },
+            ControlCommand::SetWatch => Self::SetWatch {
+                addr: hi,
+                mask: lo,
+                interval_ms: index,
+            },
+            ControlCommand::SetBusTiming => Self::SetBusTiming(BusTiming::decode(data)?),
+
+            ControlCommand::LoadProgram => Self::LoadProgram { len: index },
+            ControlCommand::RunProgram => Self::RunProgram {
+                steps: (u32::from(setup.value) << 16) | u32::from(index),
+            },
+            // named by the data stage
+            ControlCommand::StoreScript => {
+                if data.len() > SCRIPT_NAME_LEN {
+                    return Err(BridgeError::BadLength);
+                }
+
+                Self::StoreScript {
+                    slot: lo,
+                    flags: hi,
+                    name: data,
+                }
+            }
+            ControlCommand::RunScript => {
+                let &[slot] = data else {
+                    return Err(BridgeError::BadLength);
+                };
+
+                Self::RunScript {
+                    slot,
+                    steps: (u32::from(setup.value) << 16) | u32::from(index),
+                }
+            }
+            ControlCommand::EraseScript => Self::EraseScript { slot: setup.value },
+
+            ControlCommand::SelfTest => Self::SelfTest,
+
+            ControlCommand::StartCapture => Self::StartCapture(CaptureConfig::decode(data)?),
+            ControlCommand::ReadCapture => Self::ReadCapture {
+                first: setup.value,
+                count: index,
+            },
+            ControlCommand::StopCapture => Self::StopCapture,
+
+            ControlCommand::GetRecvLen => Self::GetRecvLen,
+            ControlCommand::GetSendLen => Self::GetSendLen,
+            ControlCommand::GetLastError => Self::GetLastError,
+            ControlCommand::GetInfo => Self::GetInfo,
+            ControlCommand::GetScriptInfo => Self::GetScriptInfo { slot: setup.value },
+            ControlCommand::GetBusTiming => Self::GetBusTiming,
+            ControlCommand::GetCardStatus => Self::GetCardStatus,
+            ControlCommand::GetCaptureStatus => Self::GetCaptureStatus,
+
+            ControlCommand::RebootToUSB => Self::RebootToUSB,
+        };
+
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(request: Request) {
+        let encoded = request.encode();
+
+        let decoded = if request.command().is_in() {
+            Request::decode_in(&encoded.setup)
+        } else {
+            Request::decode_out(&encoded.setup, encoded.data())
+        };
+
+        assert_eq!(decoded, Ok(request));
+    }
+
+    #[test]
+    fn every_request_round_trips() {
+        let requests = [
+            Request::Write {
+                addr: 0x12,
+                data: 0x34,
+            },
+            Request::Read { addr: 0xAB },
+            Request::WaitFor {
+                addr: 0x01,
+                mask: 0x80,
+                expected: 0x80,
+                timeout: 50,
+            },
+            Request::WriteFromBuf {
+                addr: 0x02,
+                len: 4096,
+            },
+            Request::ReadIntoBuf { addr: 0x03, len: 1 },
+            Request::WriteBitsFromBuf {
+                addr: 0x04,
+                len: 16,
+            },
+            Request::ReadBitsIntoBuf {
+                addr: 0x05,
+                bit: 7,
+                len: 2,
+            },
+            Request::ReadRangeIntoBuf {
+                range: AddrRange::new(0x10, 2, 0),
+                len: 256,
+            },
+            Request::WriteRangeFromBuf {
+                range: AddrRange::new(0xF0, 1, 16),
+                len: 32,
+            },
+            Request::ScatterGather { entries: 5 },
+            Request::SetStreamMode { enabled: true },
+            Request::SetWatch {
+                addr: 0x20,
+                mask: 0x0F,
+                interval_ms: 100,
+            },
+            Request::SetBusTiming(BusTiming::DEFAULT),
+            Request::LoadProgram { len: 1024 },
+            Request::RunProgram { steps: 0x0012_3456 },
+            Request::StoreScript {
+                slot: 3,
+                flags: 1,
+                name: b"reset",
+            },
+            Request::RunScript { slot: 3, steps: 0 },
+            Request::RunScript {
+                slot: 15,
+                steps: 0x0100_0000,
+            },
+            Request::EraseScript { slot: 15 },
+            Request::SelfTest,
+            Request::StartCapture(CaptureConfig {
+                clock_int: 1,
+                clock_frac: 128,
+                samples: 8192,
+                trigger_mask: 1 << 17,
+                trigger_value: 0,
+            }),
+            Request::ReadCapture {
+                first: 100,
+                count: 200,
+            },
+            Request::StopCapture,
+            Request::GetRecvLen,
+            Request::GetSendLen,
+            Request::GetLastError,
+            Request::GetInfo,
+            Request::GetScriptInfo { slot: 2 },
+            Request::GetBusTiming,
+            Request::GetCardStatus,
+            Request::GetCaptureStatus,
+            Request::RebootToUSB,
+        ];
+
+        for request in requests {
+            round_trip(request);
+        }
+    }
+
+    #[test]
+    fn address_is_in_the_high_byte() {
+        let encoded = Request::Write {
+            addr: 0x12,
+            data: 0x34,
+        }
+        .encode();
+
+        assert_eq!(encoded.setup.request, 0x00);
+        assert_eq!(encoded.setup.value, 0x1234);
+        assert!(encoded.data().is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_and_misdirected_requests() {
+        let setup = |request| Setup {
+            request,
+            value: 0,
+            index: 0,
+        };
+
+        assert_eq!(
+            Request::decode_out(&setup(0x7F), &[]),
+            Err(BridgeError::UnknownCommand)
+        );
+        assert_eq!(
+            Request::decode_out(&setup(ControlCommand::Read as u8), &[]),
+            Err(BridgeError::WrongDirection)
+        );
+        assert_eq!(
+            Request::decode_in(&setup(ControlCommand::Write as u8)),
+            Err(BridgeError::WrongDirection)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        let bits = Setup {
+            request: ControlCommand::ReadBitsIntoBuf as u8,
+            value: pack(0x01, 8),
+            index: 1,
+        };
+        assert_eq!(
+            Request::decode_out(&bits, &[]),
+            Err(BridgeError::BadArgument)
+        );
+
+        let timing = Setup {
+            request: ControlCommand::SetBusTiming as u8,
+            value: 0,
+            index: 0,
+        };
+        assert_eq!(
+            Request::decode_out(&timing, &[0; BUS_TIMING_LEN - 1]),
+            Err(BridgeError::BadLength)
+        );
+        assert_eq!(
+            Request::decode_out(&timing, &[0, 0, 0, 1, 1, 1, 1]),
+            Err(BridgeError::BadArgument)
+        );
+
+        let store = Setup {
+            request: ControlCommand::StoreScript as u8,
+            value: 0,
+            index: 0,
+        };
+        assert_eq!(
+            Request::decode_out(&store, &[b'a'; SCRIPT_NAME_LEN + 1]),
+            Err(BridgeError::BadLength)
+        );
+
+        let run = Setup {
+            request: ControlCommand::RunScript as u8,
+            value: 0,
+            index: 0,
+        };
+        assert_eq!(Request::decode_out(&run, &[]), Err(BridgeError::BadLength));
+    }
+}
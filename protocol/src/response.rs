@@ -0,0 +1,101 @@
+use crate::error::{BridgeError, BridgeResult};
+
+// the data stage of each IN request that isn't covered by its own module; a Read returns the
+// byte read on its own
+
+// GetRecvLen and GetSendLen (big-endian)
+pub const BUFFER_LEN_LEN: usize = 4;
+
+// matched, then the last value read
+pub const WAIT_FOR_LEN: usize = 2;
+
+// code (zero if nothing has failed since the last call), then the error count (big-endian)
+pub const LAST_ERROR_LEN: usize = 1 + 4;
+
+pub const CARD_STATUS_LEN: usize = 1;
+
+pub fn decode_buffer_len(data: &[u8]) -> BridgeResult<u32> {
+    let &[b0, b1, b2, b3] = data else {
+        return Err(BridgeError::BadLength);
+    };
+
+    Ok(u32::from_be_bytes([b0, b1, b2, b3]))
+}
+
+pub fn encode_buffer_len(len: usize) -> [u8; BUFFER_LEN_LEN] {
+    (len as u32).to_be_bytes()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitForResult {
+    pub matched: bool,
+    pub value: u8,
+}
+
+impl WaitForResult {
+    pub fn decode(data: &[u8]) -> BridgeResult<Self> {
+        let &[matched, value] = data else {
+            return Err(BridgeError::BadLength);
+        };
+
+        Ok(Self {
+            matched: matched != 0,
+            value,
+        })
+    }
+
+    pub fn encode(&self) -> [u8; WAIT_FOR_LEN] {
+        [self.matched as u8, self.value]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LastError {
+    // kept raw, so codes from a newer firmware still come through
+    pub code: u8,
+    pub count: u32,
+}
+
+impl LastError {
+    pub fn decode(data: &[u8]) -> BridgeResult<Self> {
+        let &[code, b0, b1, b2, b3] = data else {
+            return Err(BridgeError::BadLength);
+        };
+
+        Ok(Self {
+            code,
+            count: u32::from_be_bytes([b0, b1, b2, b3]),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; LAST_ERROR_LEN] {
+        let [b0, b1, b2, b3] = self.count.to_be_bytes();
+        [self.code, b0, b1, b2, b3]
+    }
+
+    // None if nothing has failed; Err with the raw code if this side doesn't know it
+    pub fn error(&self) -> Option<Result<BridgeError, u8>> {
+        (self.code != 0).then(|| BridgeError::try_from(self.code))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CardStatus {
+    pub present: bool,
+}
+
+impl CardStatus {
+    pub fn decode(data: &[u8]) -> BridgeResult<Self> {
+        let &[present] = data else {
+            return Err(BridgeError::BadLength);
+        };
+
+        Ok(Self {
+            present: present != 0,
+        })
+    }
+
+    pub fn encode(&self) -> [u8; CARD_STATUS_LEN] {
+        [self.present as u8]
+    }
+}
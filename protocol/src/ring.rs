@@ -0,0 +1,180 @@
+pub struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn free(&self) -> usize {
+        N - self.len
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    // byte `index` places after the oldest one
+    pub fn get(&self, index: usize) -> u8 {
+        self.buf[(self.head + index) % N]
+    }
+
+    pub fn push(&mut self, b: u8) -> bool {
+        if self.len >= N {
+            return false;
+        }
+
+        self.buf[(self.head + self.len) % N] = b;
+        self.len += 1;
+        true
+    }
+
+    // all or nothing
+    pub fn extend(&mut self, data: &[u8]) -> bool {
+        if data.len() > self.free() {
+            return false;
+        }
+
+        let tail = (self.head + self.len) % N;
+        let first = data.len().min(N - tail);
+
+        self.buf[tail..tail + first].copy_from_slice(&data[..first]);
+        self.buf[..data.len() - first].copy_from_slice(&data[first..]);
+        self.len += data.len();
+        true
+    }
+
+    // copies out as much as fits without consuming it
+    pub fn peek(&self, out: &mut [u8]) -> usize {
+        let amount = out.len().min(self.len);
+        let first = amount.min(N - self.head);
+
+        out[..first].copy_from_slice(&self.buf[self.head..self.head + first]);
+        out[first..amount].copy_from_slice(&self.buf[..amount - first]);
+        amount
+    }
+
+    pub fn consume(&mut self, amount: usize) {
+        let amount = amount.min(self.len);
+
+        self.head = (self.head + amount) % N;
+        self.len -= amount;
+    }
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // empty, with the head `offset` bytes in so that later bytes wrap round the end
+    fn wrapped<const N: usize>(offset: usize) -> RingBuffer<N> {
+        let mut ring = RingBuffer::new();
+        assert!(ring.extend(&[0xEE; N][..offset]));
+        ring.consume(offset);
+        ring
+    }
+
+    #[test]
+    fn extend_wraps_around() {
+        let mut ring = wrapped::<8>(6);
+
+        assert!(ring.extend(&[1, 2, 3, 4, 5]));
+        assert_eq!(ring.len(), 5);
+        assert_eq!(ring.free(), 3);
+        assert_eq!([0, 1, 2, 3, 4].map(|i| ring.get(i)), [1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn push_wraps_around() {
+        let mut ring = wrapped::<4>(3);
+
+        for b in 1..=4 {
+            assert!(ring.push(b));
+        }
+        assert!(!ring.push(5));
+
+        let mut out = [0; 4];
+        assert_eq!(ring.peek(&mut out), 4);
+        assert_eq!(out, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn peek_leaves_data_and_consume_removes_it() {
+        let mut ring = wrapped::<8>(5);
+        assert!(ring.extend(&[1, 2, 3, 4, 5, 6]));
+
+        let mut out = [0; 4];
+        assert_eq!(ring.peek(&mut out), 4);
+        assert_eq!(out, [1, 2, 3, 4]);
+        assert_eq!(ring.len(), 6);
+
+        ring.consume(4);
+        assert_eq!(ring.len(), 2);
+
+        // only as much as is held
+        let mut out = [0; 4];
+        assert_eq!(ring.peek(&mut out), 2);
+        assert_eq!(out, [5, 6, 0, 0]);
+    }
+
+    #[test]
+    fn consume_stops_at_len() {
+        let mut ring = RingBuffer::<8>::new();
+        assert!(ring.extend(&[1, 2, 3]));
+
+        ring.consume(10);
+        assert!(ring.is_empty());
+        assert_eq!(ring.free(), 8);
+    }
+
+    #[test]
+    fn extend_is_all_or_nothing_when_full() {
+        let mut ring = wrapped::<8>(3);
+        assert!(ring.extend(&[1, 2, 3, 4, 5, 6]));
+
+        assert!(!ring.extend(&[7, 8, 9]));
+        assert_eq!(ring.len(), 6);
+
+        assert!(ring.extend(&[7, 8]));
+        assert_eq!(ring.free(), 0);
+        assert!(!ring.extend(&[9]));
+        assert!(ring.extend(&[]));
+
+        let mut out = [0; 8];
+        assert_eq!(ring.peek(&mut out), 8);
+        assert_eq!(out, [1, 2, 3, 4, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn clear_empties() {
+        let mut ring = wrapped::<4>(2);
+        assert!(ring.extend(&[1, 2, 3]));
+
+        ring.clear();
+        assert!(ring.is_empty());
+        assert!(ring.extend(&[4, 5, 6, 7]));
+        assert_eq!([0, 1, 2, 3].map(|i| ring.get(i)), [4, 5, 6, 7]);
+    }
+}
@@ -0,0 +1,55 @@
+use crate::error::{BridgeError, BridgeResult};
+
+// pins tested, then those that failed each stage (one bit per GPIO, big-endian)
+pub const SELF_TEST_LEN: usize = 4 * size_of::<u32>();
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SelfTest {
+    pub tested: u32,
+    // didn't read high when released
+    pub pull_up: u32,
+    // didn't follow a single line driven high among low ones
+    pub walking_one: u32,
+    // didn't follow a single line driven low among high ones
+    pub walking_zero: u32,
+}
+
+impl SelfTest {
+    pub fn decode(data: &[u8]) -> BridgeResult<Self> {
+        if data.len() != SELF_TEST_LEN {
+            return Err(BridgeError::BadLength);
+        }
+
+        let mut masks = data
+            .chunks_exact(size_of::<u32>())
+            .map(|chunk| u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]));
+        let mut next = || masks.next().unwrap_or(0);
+
+        Ok(Self {
+            tested: next(),
+            pull_up: next(),
+            walking_one: next(),
+            walking_zero: next(),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; SELF_TEST_LEN] {
+        let mut buf = [0; SELF_TEST_LEN];
+
+        for (chunk, mask) in buf.chunks_exact_mut(size_of::<u32>()).zip([
+            self.tested,
+            self.pull_up,
+            self.walking_one,
+            self.walking_zero,
+        ]) {
+            chunk.copy_from_slice(&mask.to_be_bytes());
+        }
+
+        buf
+    }
+
+    // every line that failed any stage
+    pub fn failed(&self) -> u32 {
+        self.pull_up | self.walking_one | self.walking_zero
+    }
+}
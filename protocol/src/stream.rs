@@ -0,0 +1,100 @@
+use crate::command::ControlCommand;
+use crate::error::{BridgeError, BridgeResult};
+
+// opcode, address, bit (ReadBitsIntoBuf only, otherwise zero), length (big-endian; counted in
+// entries for ScatterGather)
+pub const STREAM_HEADER_LEN: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamOp {
+    pub cmd: ControlCommand,
+    pub addr: u8,
+    pub bit: u8,
+    pub remaining: u16,
+}
+
+impl StreamOp {
+    pub fn decode(header: &[u8]) -> BridgeResult<Self> {
+        let &[cmd, addr, bit, hi, lo] = header else {
+            return Err(BridgeError::BadLength);
+        };
+
+        let cmd = ControlCommand::try_from(cmd).map_err(|_| BridgeError::BadStreamFrame)?;
+
+        match cmd {
+            ControlCommand::WriteFromBuf
+            | ControlCommand::ReadIntoBuf
+            | ControlCommand::WriteBitsFromBuf
+            | ControlCommand::ScatterGather => {
+                if bit != 0 {
+                    return Err(BridgeError::BadStreamFrame);
+                }
+            }
+            ControlCommand::ReadBitsIntoBuf => {
+                if bit >= u8::BITS as u8 {
+                    return Err(BridgeError::BadStreamFrame);
+                }
+            }
+            _ => return Err(BridgeError::BadStreamFrame),
+        }
+
+        Ok(Self {
+            cmd,
+            addr,
+            bit,
+            remaining: u16::from_be_bytes([hi, lo]),
+        })
+    }
+
+    pub fn encode(&self) -> [u8; STREAM_HEADER_LEN] {
+        let [hi, lo] = self.remaining.to_be_bytes();
+        [self.cmd as u8, self.addr, self.bit, hi, lo]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let op = StreamOp {
+            cmd: ControlCommand::ReadBitsIntoBuf,
+            addr: 0x12,
+            bit: 5,
+            remaining: 0x0304,
+        };
+
+        assert_eq!(op.encode(), [0x13, 0x12, 5, 0x03, 0x04]);
+        assert_eq!(StreamOp::decode(&op.encode()), Ok(op));
+    }
+
+    #[test]
+    fn rejects_bad_bits() {
+        let bits = |cmd: ControlCommand, bit| {
+            StreamOp::decode(&[cmd as u8, 0x12, bit, 0, 1]).map(|op| op.bit)
+        };
+
+        assert_eq!(bits(ControlCommand::ReadBitsIntoBuf, 7), Ok(7));
+        assert_eq!(
+            bits(ControlCommand::ReadBitsIntoBuf, 8),
+            Err(BridgeError::BadStreamFrame)
+        );
+        assert_eq!(
+            bits(ControlCommand::WriteFromBuf, 1),
+            Err(BridgeError::BadStreamFrame)
+        );
+    }
+
+    #[test]
+    fn rejects_control_only_commands() {
+        assert_eq!(
+            StreamOp::decode(&[ControlCommand::Read as u8, 0x12, 0, 0, 1]),
+            Err(BridgeError::BadStreamFrame)
+        );
+        assert_eq!(
+            StreamOp::decode(&[0x10, 0x12, 0, 0]),
+            Err(BridgeError::BadLength)
+        );
+    }
+}
@@ -0,0 +1,112 @@
+use crate::error::{BridgeError, BridgeResult};
+
+// clock divisor (16.8 fixed point, big-endian integer part), then setup, strobe, sample, hold
+pub const BUS_TIMING_LEN: usize = 2 + 1 + 4;
+
+// the longest phase the bus programs can be assembled with
+pub const MAX_PHASE_CYCLES: u8 = 136;
+
+// besides the phases, each cycle has a pull, and writes an `out` before their setup
+const FIXED_CYCLES: u64 = 2;
+
+// each phase is a number of state machine cycles, at least one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusTiming {
+    pub clock_int: u16,
+    pub clock_frac: u8,
+    // address out to data released (reads) or CLK high (writes)
+    pub setup: u8,
+    // CLK high, on writes
+    pub strobe: u8,
+    // data released to sampled, on reads
+    pub sample: u8,
+    // end of the cycle to the next address
+    pub hold: u8,
+}
+
+impl BusTiming {
+    pub const DEFAULT: Self = Self {
+        clock_int: 7,
+        clock_frac: 0,
+        setup: 1,
+        strobe: 1,
+        sample: 2,
+        hold: 1,
+    };
+
+    pub fn decode(data: &[u8]) -> BridgeResult<Self> {
+        if data.len() != BUS_TIMING_LEN {
+            return Err(BridgeError::BadLength);
+        }
+
+        let timing = Self {
+            clock_int: u16::from_be_bytes([data[0], data[1]]),
+            clock_frac: data[2],
+            setup: data[3],
+            strobe: data[4],
+            sample: data[5],
+            hold: data[6],
+        };
+
+        // an integer part of zero would mean 65536, which is never what anyone wants
+        if timing.clock_int == 0 || data[3..].iter().any(|&c| c == 0 || c > MAX_PHASE_CYCLES) {
+            return Err(BridgeError::BadArgument);
+        }
+
+        Ok(timing)
+    }
+
+    // the longest a single read or write can take with the state machines run from `sys_hz`,
+    // rounded up
+    pub fn cycle_ns(&self, sys_hz: u32) -> u64 {
+        let cycles = FIXED_CYCLES
+            + u64::from(self.setup)
+            + u64::from(self.strobe.max(self.sample))
+            + u64::from(self.hold);
+        let divisor = u64::from(self.clock_int) * 256 + u64::from(self.clock_frac);
+
+        (cycles * divisor * 1_000_000_000).div_ceil(u64::from(sys_hz) * 256)
+    }
+
+    pub fn encode(&self) -> [u8; BUS_TIMING_LEN] {
+        let [hi, lo] = self.clock_int.to_be_bytes();
+
+        [
+            hi,
+            lo,
+            self.clock_frac,
+            self.setup,
+            self.strobe,
+            self.sample,
+            self.hold,
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYS_HZ: u32 = 150_000_000;
+
+    #[test]
+    fn default_cycles_are_a_fraction_of_a_microsecond() {
+        // 6 state machine cycles at 150 MHz / 7
+        assert_eq!(BusTiming::DEFAULT.cycle_ns(SYS_HZ), 280);
+    }
+
+    #[test]
+    fn the_slowest_cycles_take_around_180ms() {
+        let timing = BusTiming {
+            clock_int: u16::MAX,
+            clock_frac: u8::MAX,
+            setup: MAX_PHASE_CYCLES,
+            strobe: MAX_PHASE_CYCLES,
+            sample: MAX_PHASE_CYCLES,
+            hold: MAX_PHASE_CYCLES,
+        };
+
+        let ns = timing.cycle_ns(SYS_HZ);
+        assert!(ns > 179_000_000 && ns < 181_000_000);
+    }
+}
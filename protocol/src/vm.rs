@@ -0,0 +1,449 @@
+use crate::error::{BridgeError, BridgeResult};
+
+const COUNTERS: usize = 4;
+
+// the longest a single Delay waits
+pub const MAX_DELAY_US: u64 = u16::MAX as u64;
+
+// what a program can do to the outside world
+pub trait Machine {
+    fn read_reg(&mut self, addr: u8) -> BridgeResult<u8>;
+    fn write_reg(&mut self, addr: u8, data: u8) -> BridgeResult<()>;
+    fn emit(&mut self, b: u8) -> BridgeResult<()>;
+    fn delay_us(&mut self, us: u16);
+    fn timed_out(&self) -> bool;
+}
+
+// operands follow the opcode; jump targets and 16-bit immediates are big-endian
+#[repr(u8)]
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Halt = 0x00,
+
+    Read = 0x01,   // addr: a = [addr]
+    Write = 0x02,  // addr, imm: [addr] = imm
+    WriteA = 0x03, // addr: [addr] = a
+
+    Load = 0x10, // imm: a = imm
+    And = 0x11,  // imm: a &= imm
+    Or = 0x12,   // imm: a |= imm
+    Xor = 0x13,  // imm: a ^= imm
+    Cmp = 0x14,  // imm: z = a == imm
+
+    Jmp = 0x20,  // target
+    Jz = 0x21,   // target: jump if z
+    Jnz = 0x22,  // target: jump unless z
+    Set = 0x23,  // counter, imm16: c[counter] = imm16
+    Loop = 0x24, // counter, target: c[counter] -= 1, jump unless it reached zero
+
+    Delay = 0x30, // imm16: wait that many microseconds
+    Emit = 0x31,  // queue a to be sent to the host
+}
+
+impl TryFrom<u8> for Op {
+    type Error = u8;
+
+    fn try_from(value: u8) -> core::result::Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(Self::Halt),
+
+            0x01 => Ok(Self::Read),
+            0x02 => Ok(Self::Write),
+            0x03 => Ok(Self::WriteA),
+
+            0x10 => Ok(Self::Load),
+            0x11 => Ok(Self::And),
+            0x12 => Ok(Self::Or),
+            0x13 => Ok(Self::Xor),
+            0x14 => Ok(Self::Cmp),
+
+            0x20 => Ok(Self::Jmp),
+            0x21 => Ok(Self::Jz),
+            0x22 => Ok(Self::Jnz),
+            0x23 => Ok(Self::Set),
+            0x24 => Ok(Self::Loop),
+
+            0x30 => Ok(Self::Delay),
+            0x31 => Ok(Self::Emit),
+
+            e => Err(e),
+        }
+    }
+}
+
+pub struct Vm<'p> {
+    program: &'p [u8],
+    pc: usize,
+    a: u8,
+    z: bool,
+    counters: [u16; COUNTERS],
+}
+
+impl<'p> Vm<'p> {
+    pub fn new(program: &'p [u8]) -> Self {
+        Self {
+            program,
+            pc: 0,
+            a: 0,
+            z: false,
+            counters: [0; COUNTERS],
+        }
+    }
+
+    // runs until Halt or the end of the program, or fails once `step_limit` instructions have
+    // been executed or the machine times out
+    pub fn run(&mut self, machine: &mut impl Machine, step_limit: u32) -> BridgeResult<()> {
+        for _ in 0..step_limit {
+            if self.pc >= self.program.len() {
+                return Ok(());
+            }
+
+            if machine.timed_out() {
+                return Err(BridgeError::VmTimeout);
+            }
+
+            let op = Op::try_from(self.fetch()?).map_err(|_| BridgeError::VmBadInstruction)?;
+
+            match op {
+                Op::Halt => return Ok(()),
+
+                Op::Read => {
+                    let addr = self.fetch()?;
+                    self.a = machine.read_reg(addr)?;
+                }
+                Op::Write => {
+                    let addr = self.fetch()?;
+                    let data = self.fetch()?;
+                    machine.write_reg(addr, data)?;
+                }
+                Op::WriteA => {
+                    let addr = self.fetch()?;
+                    machine.write_reg(addr, self.a)?;
+                }
+
+                Op::Load => self.a = self.fetch()?,
+                Op::And => self.a &= self.fetch()?,
+                Op::Or => self.a |= self.fetch()?,
+                Op::Xor => self.a ^= self.fetch()?,
+                Op::Cmp => self.z = self.a == self.fetch()?,
+
+                Op::Jmp => self.pc = self.fetch_target()?,
+                Op::Jz => {
+                    let target = self.fetch_target()?;
+                    if self.z {
+                        self.pc = target;
+                    }
+                }
+                Op::Jnz => {
+                    let target = self.fetch_target()?;
+                    if !self.z {
+                        self.pc = target;
+                    }
+                }
+                Op::Set => {
+                    let counter = self.fetch_counter()?;
+                    self.counters[counter] = self.fetch_u16()?;
+                }
+                Op::Loop => {
+                    let counter = self.fetch_counter()?;
+                    let target = self.fetch_target()?;
+
+                    self.counters[counter] = self.counters[counter].saturating_sub(1);
+                    if self.counters[counter] != 0 {
+                        self.pc = target;
+                    }
+                }
+
+                Op::Delay => {
+                    let us = self.fetch_u16()?;
+                    machine.delay_us(us);
+                }
+                Op::Emit => machine.emit(self.a)?,
+            }
+        }
+
+        // the last step may have run off the end
+        if self.pc >= self.program.len() {
+            return Ok(());
+        }
+
+        Err(BridgeError::VmStepLimit)
+    }
+
+    fn fetch(&mut self) -> BridgeResult<u8> {
+        let b = *self
+            .program
+            .get(self.pc)
+            .ok_or(BridgeError::VmBadInstruction)?;
+        self.pc += 1;
+        Ok(b)
+    }
+
+    fn fetch_u16(&mut self) -> BridgeResult<u16> {
+        Ok(u16::from_be_bytes([self.fetch()?, self.fetch()?]))
+    }
+
+    fn fetch_target(&mut self) -> BridgeResult<usize> {
+        let target = usize::from(self.fetch_u16()?);
+
+        if target >= self.program.len() {
+            return Err(BridgeError::VmBadInstruction);
+        }
+
+        Ok(target)
+    }
+
+    fn fetch_counter(&mut self) -> BridgeResult<usize> {
+        let counter = usize::from(self.fetch()?);
+
+        if counter >= COUNTERS {
+            return Err(BridgeError::VmBadInstruction);
+        }
+
+        Ok(counter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HALT: u8 = Op::Halt as u8;
+    const READ: u8 = Op::Read as u8;
+    const WRITE: u8 = Op::Write as u8;
+    const WRITE_A: u8 = Op::WriteA as u8;
+    const LOAD: u8 = Op::Load as u8;
+    const AND: u8 = Op::And as u8;
+    const OR: u8 = Op::Or as u8;
+    const XOR: u8 = Op::Xor as u8;
+    const CMP: u8 = Op::Cmp as u8;
+    const JMP: u8 = Op::Jmp as u8;
+    const JZ: u8 = Op::Jz as u8;
+    const JNZ: u8 = Op::Jnz as u8;
+    const SET: u8 = Op::Set as u8;
+    const LOOP: u8 = Op::Loop as u8;
+    const DELAY: u8 = Op::Delay as u8;
+    const EMIT: u8 = Op::Emit as u8;
+
+    const STEPS: u32 = 1000;
+
+    struct TestMachine {
+        registers: [u8; 256],
+        emitted: [u8; 8],
+        emitted_len: usize,
+        delayed_us: u32,
+        timed_out: bool,
+    }
+
+    impl TestMachine {
+        fn new() -> Self {
+            Self {
+                registers: [0; 256],
+                emitted: [0; 8],
+                emitted_len: 0,
+                delayed_us: 0,
+                timed_out: false,
+            }
+        }
+
+        fn emitted(&self) -> &[u8] {
+            &self.emitted[..self.emitted_len]
+        }
+    }
+
+    impl Machine for TestMachine {
+        fn read_reg(&mut self, addr: u8) -> BridgeResult<u8> {
+            Ok(self.registers[usize::from(addr)])
+        }
+
+        fn write_reg(&mut self, addr: u8, data: u8) -> BridgeResult<()> {
+            self.registers[usize::from(addr)] = data;
+            Ok(())
+        }
+
+        // fails once the send buffer would be full
+        fn emit(&mut self, b: u8) -> BridgeResult<()> {
+            let slot = self
+                .emitted
+                .get_mut(self.emitted_len)
+                .ok_or(BridgeError::BufferFull)?;
+            *slot = b;
+            self.emitted_len += 1;
+            Ok(())
+        }
+
+        fn delay_us(&mut self, us: u16) {
+            self.delayed_us += u32::from(us);
+        }
+
+        fn timed_out(&self) -> bool {
+            self.timed_out
+        }
+    }
+
+    fn run(program: &[u8], machine: &mut TestMachine) -> BridgeResult<()> {
+        Vm::new(program).run(machine, STEPS)
+    }
+
+    #[test]
+    fn reads_and_writes() {
+        let mut m = TestMachine::new();
+        m.registers[0x10] = 0x5A;
+
+        let program = [WRITE, 0x20, 0x11, READ, 0x10, WRITE_A, 0x21, EMIT];
+        run(&program, &mut m).unwrap();
+
+        assert_eq!(m.registers[0x20], 0x11);
+        assert_eq!(m.registers[0x21], 0x5A);
+        assert_eq!(m.emitted(), [0x5A]);
+    }
+
+    #[test]
+    fn does_arithmetic() {
+        let mut m = TestMachine::new();
+
+        let program = [
+            LOAD, 0xF0, EMIT, AND, 0x3C, EMIT, OR, 0x01, EMIT, XOR, 0xFF, EMIT,
+        ];
+        run(&program, &mut m).unwrap();
+
+        assert_eq!(m.emitted(), [0xF0, 0x30, 0x31, 0xCE]);
+    }
+
+    #[test]
+    fn branches_on_compare() {
+        let mut m = TestMachine::new();
+
+        // 0: a is 7, so falls through and emits 1
+        // 10: a is now 1, so falls through, emits 2 and jumps over the emits at 21 and 22
+        let program = [
+            LOAD, 7, CMP, 7, JNZ, 0, 10, LOAD, 1, EMIT, //
+            CMP, 8, JZ, 0, 22, LOAD, 2, EMIT, JMP, 0, 23, EMIT, //
+            EMIT, HALT,
+        ];
+        run(&program, &mut m).unwrap();
+
+        assert_eq!(m.emitted(), [1, 2]);
+    }
+
+    #[test]
+    fn loops_on_counters() {
+        let mut m = TestMachine::new();
+
+        let program = [SET, 3, 0, 5, READ, 0x00, OR, 0x80, EMIT, LOOP, 3, 0, 4];
+        run(&program, &mut m).unwrap();
+
+        assert_eq!(m.emitted(), [0x80; 5]);
+    }
+
+    #[test]
+    fn delays() {
+        let mut m = TestMachine::new();
+
+        run(&[DELAY, 0x01, 0x02, DELAY, 0, 3], &mut m).unwrap();
+
+        assert_eq!(m.delayed_us, 0x0102 + 3);
+    }
+
+    #[test]
+    fn stops_at_halt_or_the_end() {
+        let mut m = TestMachine::new();
+
+        run(&[HALT, WRITE, 0x00, 0xFF], &mut m).unwrap();
+        assert_eq!(m.registers[0x00], 0);
+
+        run(&[], &mut m).unwrap();
+    }
+
+    #[test]
+    fn rejects_branches_out_of_the_program() {
+        let mut m = TestMachine::new();
+
+        assert_eq!(
+            run(&[JMP, 0, 3], &mut m),
+            Err(BridgeError::VmBadInstruction)
+        );
+        assert_eq!(
+            run(&[JZ, 0x01, 0x00], &mut m),
+            Err(BridgeError::VmBadInstruction)
+        );
+        assert_eq!(
+            run(&[LOOP, 0, 0, 4], &mut m),
+            Err(BridgeError::VmBadInstruction)
+        );
+
+        // the last byte is still in bounds
+        run(&[JMP, 0, 3, HALT], &mut m).unwrap();
+    }
+
+    #[test]
+    fn rejects_bad_counters_and_opcodes() {
+        let mut m = TestMachine::new();
+
+        assert_eq!(
+            run(&[SET, COUNTERS as u8, 0, 1], &mut m),
+            Err(BridgeError::VmBadInstruction)
+        );
+        assert_eq!(run(&[0xEE], &mut m), Err(BridgeError::VmBadInstruction));
+    }
+
+    #[test]
+    fn rejects_truncated_programs() {
+        let mut m = TestMachine::new();
+
+        for program in [
+            &[READ][..],
+            &[WRITE, 0x10],
+            &[WRITE_A],
+            &[LOAD],
+            &[CMP],
+            &[JMP, 0],
+            &[SET, 0, 1],
+            &[LOOP, 0, 0],
+            &[DELAY, 1],
+        ] {
+            assert_eq!(run(program, &mut m), Err(BridgeError::VmBadInstruction));
+        }
+
+        // nothing half-decoded was carried out
+        assert_eq!(m.registers, [0; 256]);
+        assert_eq!(m.delayed_us, 0);
+    }
+
+    #[test]
+    fn stops_at_the_step_limit() {
+        let mut m = TestMachine::new();
+
+        assert_eq!(
+            Vm::new(&[JMP, 0, 0]).run(&mut m, 100),
+            Err(BridgeError::VmStepLimit)
+        );
+
+        // exactly enough steps is fine
+        let program = [LOAD, 1, EMIT];
+        Vm::new(&program).run(&mut m, 2).unwrap();
+        assert_eq!(
+            Vm::new(&program).run(&mut m, 1),
+            Err(BridgeError::VmStepLimit)
+        );
+    }
+
+    #[test]
+    fn stops_on_timeout() {
+        let mut m = TestMachine::new();
+        m.timed_out = true;
+
+        assert_eq!(run(&[WRITE, 0x10, 1], &mut m), Err(BridgeError::VmTimeout));
+        assert_eq!(m.registers[0x10], 0);
+    }
+
+    #[test]
+    fn passes_machine_errors_on() {
+        let mut m = TestMachine::new();
+
+        let program = [SET, 0, 0, 100, EMIT, LOOP, 0, 0, 4];
+
+        assert_eq!(run(&program, &mut m), Err(BridgeError::BufferFull));
+        assert_eq!(m.emitted_len, m.emitted.len());
+    }
+}
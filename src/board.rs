@@ -0,0 +1,39 @@
+use card_emu_protocol::board::Board;
+use rp235x_hal::Sio;
+use rp235x_hal::gpio::{DynPinId, FunctionSioInput, Pin, PullDown};
+use rp235x_hal::timer::{CopyableTimer0, Timer};
+
+use crate::rom::ROM;
+
+// the RP2350 the bridge runs on; the sense pin is read along with the rest of bank 0, and is
+// only held so nothing else can reconfigure it
+pub struct PicoBoard {
+    timer: Timer<CopyableTimer0>,
+    _sense: Pin<DynPinId, FunctionSioInput, PullDown>,
+}
+
+impl PicoBoard {
+    pub fn new(
+        timer: Timer<CopyableTimer0>,
+        sense: Pin<DynPinId, FunctionSioInput, PullDown>,
+    ) -> Self {
+        Self {
+            timer,
+            _sense: sense,
+        }
+    }
+}
+
+impl Board for PicoBoard {
+    fn now_us(&self) -> u64 {
+        self.timer.get_counter().ticks()
+    }
+
+    fn pins(&self) -> u32 {
+        Sio::read_bank0()
+    }
+
+    fn reboot_to_usb(&mut self) {
+        unsafe { ROM::reset_usb_boot(None, false, false) };
+    }
+}
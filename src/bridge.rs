@@ -1,80 +1,43 @@
-use rp235x_hal::dma::{Byte, HalfWord};
-use rp235x_hal::pio::{PIO0SM0, Running, Rx, StateMachine, Tx, ValidStateMachine};
 use usb_device::bus::{InterfaceNumber, UsbBus, UsbBusAllocator};
 use usb_device::class::{ControlIn, ControlOut, UsbClass};
-use usb_device::control::RequestType;
+use usb_device::control::{self, RequestType};
 use usb_device::endpoint::{EndpointAddress, EndpointIn, EndpointOut, EndpointType};
 use usb_device::{Result, UsbDirection, UsbError};
 
-use crate::rom::ROM;
+use card_emu_protocol::board::Board;
+use card_emu_protocol::bus::CardBus;
+use card_emu_protocol::capture::Sampler;
+use card_emu_protocol::event::EVENT_LEN;
+use card_emu_protocol::handler::{BRIDGE_READ_SIZE, BRIDGE_WRITE_SIZE, CONTROL_IN_LEN, Handler};
+use card_emu_protocol::program::ScriptStore;
+use card_emu_protocol::{BridgeError, Setup};
 
-// maximum size allowed for bulk endpoints
-const BRIDGE_WRITE_SIZE: usize = 64;
-const BRIDGE_READ_SIZE: usize = 64;
+// four events per packet, polled every millisecond
+const EVENT_PACKET_SIZE: usize = 4 * EVENT_LEN;
+const EVENT_INTERVAL_MS: u8 = 1;
 
-pub struct Bridge<'a, B: UsbBus, ReadSM, WriteSM>
+// the USB side of the bridge; everything else is up to the handler
+pub struct Bridge<'a, B: UsbBus, Bus, Bd, S, Sc>
 where
-    ReadSM: ValidStateMachine,
-    WriteSM: ValidStateMachine,
+    Bus: CardBus,
+    Bd: Board,
+    S: Sampler,
+    Sc: ScriptStore,
 {
     iface: InterfaceNumber,
     read_ep: EndpointOut<'a, B>,
     write_ep: EndpointIn<'a, B>,
+    event_ep: EndpointIn<'a, B>,
 
-    read_sm: StateMachine<ReadSM, Running>,
-    write_sm: StateMachine<WriteSM, Running>,
-    read_rx: Rx<ReadSM, Byte>,
-    read_tx: Tx<ReadSM, Byte>,
-    write_tx: Tx<WriteSM, HalfWord>,
-
-    send_buffer: [u8; BRIDGE_WRITE_SIZE],
-    send_len: usize,
-    recv_buffer: [u8; BRIDGE_READ_SIZE],
-    recv_len: usize,
-}
-
-#[repr(u8)]
-#[derive(Debug, Clone, Copy)]
-enum ControlCommand {
-    Write = 0x00,
-    Read = 0x01,
-
-    WriteFromBuf = 0x10,
-    ReadIntoBuf = 0x11,
-    WriteBitsFromBuf = 0x12,
-
-    GetRecvLen = 0x80,
-    GetSendLen = 0x81,
-
-    RebootToUSB = 0xFF,
-}
-
-impl TryFrom<u8> for ControlCommand {
-    type Error = u8;
-
-    fn try_from(value: u8) -> core::result::Result<Self, Self::Error> {
-        match value {
-            0x00 => Ok(Self::Write),
-            0x01 => Ok(Self::Read),
-
-            0x10 => Ok(Self::WriteFromBuf),
-            0x11 => Ok(Self::ReadIntoBuf),
-            0x12 => Ok(Self::WriteBitsFromBuf),
-
-            0x80 => Ok(Self::GetRecvLen),
-            0x81 => Ok(Self::GetSendLen),
-
-            0xFF => Ok(Self::RebootToUSB),
-
-            e => Err(e),
-        }
-    }
+    handler: Handler<Bus, Bd, S, Sc>,
 }
 
-impl<'a, B: UsbBus, ReadSM, WriteSM> UsbClass<B> for Bridge<'a, B, ReadSM, WriteSM>
+impl<'a, B: UsbBus, Bus, Bd, S, Sc> UsbClass<B> for Bridge<'a, B, Bus, Bd, S, Sc>
 where
-    ReadSM: ValidStateMachine,
-    WriteSM: ValidStateMachine,
+    Bus: CardBus,
+    Bd: Board,
+    S: Sampler,
+    Sc: ScriptStore,
 {
     fn get_configuration_descriptors(
         &self,
@@ -83,185 +46,60 @@ where
         writer.interface(self.iface, 0xFF, 0xFF, 0xFF)?;
         writer.endpoint(&self.write_ep)?;
         writer.endpoint(&self.read_ep)?;
+        writer.endpoint(&self.event_ep)?;
         Ok(())
     }
 
     fn reset(&mut self) {
-        self.send_len = 0;
-        self.recv_len = 0;
+        self.handler.reset();
     }
 
     fn control_in(&mut self, xfer: ControlIn<B>) {
-        let req = xfer.request();
-
-        if req.request_type == RequestType::Vendor {
-            let cmd = ControlCommand::try_from(req.request);
-
-            match cmd {
-                Ok(ControlCommand::Read) => {
-                    if self.read_tx.write_u16_replicated(req.value) == false {
-                        xfer.reject().unwrap();
-                        return;
-                    }
-
-                    while self.read_rx.is_empty() {
-                        // wait
-                    }
+        let req = *xfer.request();
 
-                    if let Some(b) = self.read_rx.read() {
-                        xfer.accept(|buf| {
-                            buf[0] = b as u8;
-                            Ok(1)
-                        })
-                        .unwrap();
-                    } else {
-                        xfer.reject().unwrap();
-                    }
-                }
-
-                Ok(ControlCommand::GetRecvLen) => xfer
-                    .accept(|buf| {
-                        buf[0..size_of::<u32>()]
-                            .copy_from_slice(&(self.recv_len as u32).to_be_bytes());
-                        Ok(size_of::<u32>())
-                    })
-                    .unwrap(),
+        if req.request_type != RequestType::Vendor {
+            return;
+        }
 
-                Ok(ControlCommand::GetSendLen) => xfer
-                    .accept(|buf| {
-                        buf[0..size_of::<u32>()]
-                            .copy_from_slice(&(self.send_len as u32).to_be_bytes());
-                        Ok(size_of::<u32>())
-                    })
-                    .unwrap(),
+        let mut data = [0; CONTROL_IN_LEN];
 
-                Ok(c) => {
-                    todo!("unimplemented command: {c:?}");
-                }
+        // failures have already been recorded by the handler
+        let res = match self.handler.control_in(&Self::setup(&req), &mut data) {
+            Ok(n) => xfer.accept_with(&data[..n]),
+            Err(_) => xfer.reject(),
+        };
 
-                Err(_) => {
-                    xfer.reject().unwrap();
-                }
-            }
+        if res.is_err() {
+            self.handler.fail(BridgeError::Usb);
         }
     }
 
     fn control_out(&mut self, xfer: ControlOut<B>) {
-        let req = xfer.request();
-        if req.request_type == RequestType::Vendor {
-            let cmd = ControlCommand::try_from(req.request);
-
-            match cmd {
-                Ok(ControlCommand::RebootToUSB) => {
-                    unsafe { ROM::reset_usb_boot(None, false, false) };
-                }
-
-                Ok(ControlCommand::Write) => {
-                    while self.write_tx.is_full() {
-                        // do nothing
-                    }
-
-                    if self.write_tx.write_u16_replicated(req.value) {
-                        xfer.accept().unwrap();
-                    } else {
-                        xfer.reject().unwrap();
-                    }
-
-                    while !self.write_tx.is_empty() {
-                        // do nothing
-                    }
-                }
-
-                Ok(ControlCommand::WriteFromBuf) => {
-                    let to_write = usize::from(req.index);
-
-                    if to_write > self.recv_len {
-                        xfer.reject().unwrap();
-                        return;
-                    }
-
-                    for &b in &self.recv_buffer[..to_write] {
-                        while self.write_tx.is_full() {
-                            // do nothing
-                        }
-
-                        if self.write_tx.write_u16_replicated(req.value | u16::from(b)) == false {
-                            xfer.reject().unwrap();
-                            return;
-                        }
-                    }
+        let req = *xfer.request();
 
-                    self.recv_buffer.copy_within(to_write.., 0);
-                    self.recv_len -= to_write;
-
-                    while !self.write_tx.is_empty() {
-                        // do nothing
-                    }
-
-                    xfer.accept().unwrap();
-                }
-
-                Ok(ControlCommand::WriteBitsFromBuf) => {
-                    let to_write = usize::from(req.index);
-
-                    if to_write > self.recv_len {
-                        xfer.reject().unwrap();
-                        return;
-                    }
-
-                    for &b in &self.recv_buffer[..to_write] {
-                        for i in 0..u8::BITS {
-                            while self.write_tx.is_full() {
-                                // do nothing
-                            }
-
-                            if self
-                                .write_tx
-                                .write_u16_replicated(req.value | u16::from((b >> i) & 1))
-                                == false
-                            {
-                                xfer.reject().unwrap();
-                                return;
-                            }
-                        }
-                    }
-
-                    self.recv_buffer.copy_within(to_write.., 0);
-                    self.recv_len -= to_write;
-
-                    while !self.write_tx.is_empty() {
-                        // do nothing
-                    }
-
-                    xfer.accept().unwrap();
-                }
+        if req.request_type != RequestType::Vendor {
+            return;
+        }
 
-                Ok(c) => {
-                    todo!("unimplemented command: {c:?}");
-                }
+        let res = match self.handler.control_out(&Self::setup(&req), xfer.data()) {
+            Ok(()) => xfer.accept(),
+            Err(_) => xfer.reject(),
+        };
 
-                Err(_) => {
-                    xfer.reject().unwrap();
-                }
-            }
+        if res.is_err() {
+            self.handler.fail(BridgeError::Usb);
         }
     }
 }
 
-impl<'a, B: UsbBus, ReadSM, WriteSM> Bridge<'a, B, ReadSM, WriteSM>
+impl<'a, B: UsbBus, Bus, Bd, S, Sc> Bridge<'a, B, Bus, Bd, S, Sc>
 where
-    ReadSM: ValidStateMachine,
-    WriteSM: ValidStateMachine,
+    Bus: CardBus,
+    Bd: Board,
+    S: Sampler,
+    Sc: ScriptStore,
 {
-    pub fn new(
-        alloc: &'a UsbBusAllocator<B>,
-        read: (
-            StateMachine<ReadSM, Running>,
-            Rx<ReadSM, Byte>,
-            Tx<ReadSM, Byte>,
-        ),
-        write: (StateMachine<WriteSM, Running>, Tx<WriteSM, HalfWord>),
-    ) -> Self {
+    pub fn new(alloc: &'a UsbBusAllocator<B>, handler: Handler<Bus, Bd, S, Sc>) -> Self {
         Self {
             iface: alloc.interface(),
             write_ep: alloc
@@ -280,47 +118,68 @@ where
                     1,
                 )
                 .expect("alloc_ep failed"),
-            read_sm: read.0,
-            write_sm: write.0,
-            read_rx: read.1,
-            read_tx: read.2,
-            write_tx: write.1,
-            send_buffer: [0; BRIDGE_WRITE_SIZE],
-            send_len: 0,
-            recv_buffer: [0; BRIDGE_READ_SIZE],
-            recv_len: 0,
+            event_ep: alloc
+                .alloc(
+                    Some(EndpointAddress::from_parts(0x03, UsbDirection::In)),
+                    EndpointType::Interrupt,
+                    EVENT_PACKET_SIZE as _,
+                    EVENT_INTERVAL_MS,
+                )
+                .expect("alloc_ep failed"),
+            handler,
         }
     }
 
+    // leaves the packet in the endpoint (so the host is NAKed) until there's room for it
     pub fn read(&mut self) -> Result<usize> {
-        if self.recv_len >= self.recv_buffer.len() {
+        if self.handler.can_receive() == false {
             return Err(UsbError::WouldBlock);
         }
-        let amount = self.read_ep.read(&mut self.recv_buffer[self.recv_len..])?;
-        self.recv_len += amount;
+
+        let mut packet = [0; BRIDGE_READ_SIZE];
+        let amount = self.read_ep.read(&mut packet)?;
+        self.handler.receive(&packet[..amount]);
         Ok(amount)
     }
 
     pub fn write(&mut self) -> Result<usize> {
-        if self.send_len == 0 {
+        let mut packet = [0; BRIDGE_WRITE_SIZE];
+        let len = self.handler.pending(&mut packet);
+
+        if len == 0 {
             return Err(UsbError::WouldBlock);
         }
-        let res = self.write_ep.write(&self.send_buffer[..self.send_len]);
-        if res.is_ok() {
-            let amount = *res.as_ref().unwrap();
-            if amount > 0 {
-                self.send_buffer.copy_within((amount)..(self.send_len), 0);
-                self.send_len -= amount;
-            }
-        }
-        res
+
+        let amount = self.write_ep.write(&packet[..len])?;
+        self.handler.sent(amount);
+        Ok(amount)
     }
 
-    pub fn receive(&mut self, amount: usize) -> Result<()> {
-        Ok(())
+    pub fn run_boot_script(&mut self) {
+        self.handler.run_boot_script();
     }
 
-    pub fn clear(&mut self, amount: usize) -> Result<()> {
-        Ok(())
+    pub fn update(&mut self) {
+        self.handler.update();
+
+        let mut packet = [0; EVENT_PACKET_SIZE];
+        let len = self.handler.pending_events(&mut packet);
+
+        if len == 0 {
+            return;
+        }
+
+        // WouldBlock until the host has collected the previous packet
+        if let Ok(n) = self.event_ep.write(&packet[..len]) {
+            self.handler.events_sent(n);
+        }
+    }
+
+    fn setup(req: &control::Request) -> Setup {
+        Setup {
+            request: req.request,
+            value: req.value,
+            index: req.index,
+        }
     }
 }
@@ -0,0 +1,512 @@
+use rp235x_hal::Sio;
+use rp235x_hal::dma::{Byte, HalfWord, SingleChannel};
+use rp235x_hal::pio::{PinDir, PinState, Running, Rx, StateMachine, Tx, ValidStateMachine};
+use rp235x_hal::timer::{CopyableTimer0, Timer};
+
+use card_emu_protocol::bus::CardBus;
+use card_emu_protocol::info::PinMap;
+use card_emu_protocol::selftest::SelfTest;
+use card_emu_protocol::timing::BusTiming;
+use card_emu_protocol::{BridgeError, BridgeResult, pack};
+
+use crate::dma::{DmaBuffer, DmaChannel};
+use crate::selftest::pins_in;
+use crate::timing::{BusPrograms, BusSm};
+
+// halfwords of address|data queued for the write state machine at once
+pub const DMA_WRITE_LEN: usize = 4096;
+// reads performed per DMA transfer
+pub const DMA_READ_LEN: usize = 1024;
+
+// how long to wait on a PIO FIFO before giving up, on top of however long the cycles queued
+// ahead take at the current timing
+const BUS_TIMEOUT_US: u64 = 10_000;
+
+// as many cycles as the deepest (joined) FIFO holds, plus the one in progress
+const QUEUED_CYCLES: usize = 8 + 1;
+
+// how long the self-test lets lines settle before reading them back; the pull-ups are weak
+const DRIVE_SETTLE_US: u16 = 10;
+const PULL_UP_SETTLE_US: u16 = 200;
+
+type WriteDma<CH, SM> = DmaChannel<CH, DmaBuffer<u16, DMA_WRITE_LEN>, Tx<SM, HalfWord>>;
+type FeedDma<CH, SM> = DmaChannel<CH, DmaBuffer<u16, DMA_READ_LEN>, Tx<SM, HalfWord>>;
+type DrainDma<CH, SM> = DmaChannel<CH, Rx<SM, Byte>, DmaBuffer<u8, DMA_READ_LEN>>;
+
+// one state machine for reads and one for writes, with DMA for anything longer than a single
+// access
+pub struct PioBus<ReadSM, WriteSM, WriteCh, FeedCh, DrainCh>
+where
+    ReadSM: ValidStateMachine,
+    WriteSM: ValidStateMachine,
+    WriteCh: SingleChannel,
+    FeedCh: SingleChannel,
+    DrainCh: SingleChannel,
+{
+    timer: Timer<CopyableTimer0>,
+    sys_hz: u32,
+    cycle_ns: u64,
+    pins: PinMap,
+    attached: bool,
+    read_sm: BusSm<ReadSM>,
+    write_sm: BusSm<WriteSM>,
+    programs: BusPrograms,
+    write_dma: WriteDma<WriteCh, WriteSM>,
+    write_deadline: u64,
+    read_feed: FeedDma<FeedCh, ReadSM>,
+    read_drain: DrainDma<DrainCh, ReadSM>,
+}
+
+impl<ReadSM, WriteSM, WriteCh, FeedCh, DrainCh> PioBus<ReadSM, WriteSM, WriteCh, FeedCh, DrainCh>
+where
+    ReadSM: ValidStateMachine,
+    WriteSM: ValidStateMachine,
+    WriteCh: SingleChannel,
+    FeedCh: SingleChannel,
+    DrainCh: SingleChannel,
+{
+    pub fn new(
+        timer: Timer<CopyableTimer0>,
+        sys_hz: u32,
+        pins: PinMap,
+        read: (
+            StateMachine<ReadSM, Running>,
+            Rx<ReadSM, Byte>,
+            Tx<ReadSM, HalfWord>,
+        ),
+        write: (StateMachine<WriteSM, Running>, Tx<WriteSM, HalfWord>),
+        programs: BusPrograms,
+        dma: (WriteCh, FeedCh, DrainCh),
+        buffers: (
+            &'static mut [u16; DMA_WRITE_LEN],
+            &'static mut [u16; DMA_READ_LEN],
+            &'static mut [u8; DMA_READ_LEN],
+        ),
+    ) -> Self {
+        Self {
+            timer,
+            sys_hz,
+            cycle_ns: BusTiming::DEFAULT.cycle_ns(sys_hz),
+            pins,
+            attached: true,
+            read_sm: BusSm::new(read.0),
+            write_sm: BusSm::new(write.0),
+            programs,
+            write_dma: DmaChannel::new(dma.0, DmaBuffer::new(buffers.0), write.1),
+            write_deadline: 0,
+            read_feed: DmaChannel::new(dma.1, DmaBuffer::new(buffers.1), read.2),
+            read_drain: DmaChannel::new(dma.2, read.1, DmaBuffer::new(buffers.2)),
+        }
+    }
+
+    // long enough for `cycles` more than could already be queued
+    fn deadline(&self, cycles: usize) -> u64 {
+        let ns = (QUEUED_CYCLES + cycles) as u64 * self.cycle_ns;
+        self.timer.get_counter().ticks() + BUS_TIMEOUT_US + ns.div_ceil(1000)
+    }
+
+    fn expired(&self, deadline: u64) -> bool {
+        self.timer.get_counter().ticks() >= deadline
+    }
+
+    fn delay_us(&self, us: u16) {
+        let end = self.timer.get_counter().ticks() + u64::from(us);
+
+        while !self.expired(end) {
+            // wait
+        }
+    }
+
+    fn abort_writes(&mut self) {
+        self.write_dma.abort();
+        self.write_sm.clear_fifos();
+    }
+
+    fn write_idle(&mut self) -> BridgeResult<()> {
+        while !self.write_dma.poll() {
+            if self.expired(self.write_deadline) {
+                self.abort_writes();
+                return Err(BridgeError::DmaTimeout);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_tx(&mut self) -> BridgeResult<&mut Tx<WriteSM, HalfWord>> {
+        self.write_idle()?;
+        self.write_dma
+            .idle()
+            .map(|(_, tx)| tx)
+            .ok_or(BridgeError::DmaBusy)
+    }
+
+    fn read_tx(&mut self) -> BridgeResult<&mut Tx<ReadSM, HalfWord>> {
+        self.read_feed
+            .idle()
+            .map(|(_, tx)| tx)
+            .ok_or(BridgeError::DmaBusy)
+    }
+
+    fn read_rx(&mut self) -> BridgeResult<&mut Rx<ReadSM, Byte>> {
+        self.read_drain
+            .idle()
+            .map(|(rx, _)| rx)
+            .ok_or(BridgeError::DmaBusy)
+    }
+
+    // waits for both state machines to go idle, so nothing is cut off halfway through a cycle;
+    // they stop wherever they stalled, which isn't necessarily the pull at the wrap target
+    fn park(&mut self) -> BridgeResult<()> {
+        if !self.attached {
+            // release has already stopped them
+            return Ok(());
+        }
+
+        self.flush()?;
+
+        let deadline = self.deadline(0);
+
+        while !(self.read_sm.stalled() && self.write_sm.stalled()) {
+            if self.expired(deadline) {
+                return Err(BridgeError::FlushTimeout);
+            }
+        }
+
+        self.read_sm.stop();
+        self.write_sm.stop();
+
+        Ok(())
+    }
+
+    // performs up to DMA_READ_LEN reads, numbered from `first`, leaving the results in the drain
+    // buffer
+    fn read_dma(
+        &mut self,
+        first: usize,
+        count: usize,
+        addr: &mut impl FnMut(usize) -> u8,
+    ) -> BridgeResult<usize> {
+        let Some((feed, _)) = self.read_feed.idle() else {
+            return Err(BridgeError::DmaBusy);
+        };
+
+        feed.clear();
+
+        while feed.len() < count && feed.push(pack(addr(first + feed.len()), 0)) {
+            // fill
+        }
+
+        let len = feed.len();
+
+        let Some((_, data)) = self.read_drain.idle() else {
+            return Err(BridgeError::DmaBusy);
+        };
+
+        data.set_len(len);
+
+        // drain first so that nothing is pushed before it's ready to be collected
+        self.read_drain.start();
+        self.read_feed.start();
+
+        let deadline = self.deadline(len);
+
+        loop {
+            let fed = self.read_feed.poll();
+            let drained = self.read_drain.poll();
+
+            if fed && drained {
+                break;
+            }
+
+            if self.expired(deadline) {
+                self.read_drain.abort();
+                self.read_feed.abort();
+                self.read_sm.clear_fifos();
+                return Err(BridgeError::DmaTimeout);
+            }
+        }
+
+        Ok(len)
+    }
+
+    // drives each line in `mask` to `level` in turn with the rest at the opposite level,
+    // returning the lines that didn't read back as expected
+    fn walk(&mut self, mask: u32, level: PinState) -> u32 {
+        let other = match level {
+            PinState::High => PinState::Low,
+            PinState::Low => PinState::High,
+        };
+
+        let mut failed = 0;
+
+        self.set_pins(mask, other);
+
+        for pin in pins_in(mask) {
+            self.read_sm.set_pins([(pin, level)]);
+            self.delay_us(DRIVE_SETTLE_US);
+
+            let expected = match level {
+                PinState::High => 1 << pin,
+                PinState::Low => mask & !(1 << pin),
+            };
+
+            failed |= (Sio::read_bank0() ^ expected) & mask;
+
+            self.read_sm.set_pins([(pin, other)]);
+        }
+
+        failed
+    }
+
+    fn set_pins(&mut self, mask: u32, level: PinState) {
+        self.read_sm.set_pins(pins_in(mask).map(|pin| (pin, level)));
+    }
+
+    fn set_pindirs(&mut self, mask: u32, dir: PinDir) {
+        self.read_sm
+            .set_pindirs(pins_in(mask).map(|pin| (pin, dir)));
+    }
+}
+
+impl<ReadSM, WriteSM, WriteCh, FeedCh, DrainCh> CardBus
+    for PioBus<ReadSM, WriteSM, WriteCh, FeedCh, DrainCh>
+where
+    ReadSM: ValidStateMachine,
+    WriteSM: ValidStateMachine,
+    WriteCh: SingleChannel,
+    FeedCh: SingleChannel,
+    DrainCh: SingleChannel,
+{
+    fn read(&mut self, addr: u8) -> BridgeResult<u8> {
+        self.flush()?;
+
+        let deadline = self.deadline(0);
+
+        while self.read_tx()?.is_full() {
+            if self.expired(deadline) {
+                self.read_sm.clear_fifos();
+                return Err(BridgeError::ReadRequestTimeout);
+            }
+        }
+
+        if self.read_tx()?.write_u16_replicated(pack(addr, 0)) == false {
+            return Err(BridgeError::ReadRequestTimeout);
+        }
+
+        let deadline = self.deadline(0);
+
+        while self.read_rx()?.is_empty() {
+            if self.expired(deadline) {
+                self.read_sm.clear_fifos();
+                return Err(BridgeError::ReadResponseTimeout);
+            }
+        }
+
+        self.read_rx()?
+            .read()
+            .map(|b| b as u8)
+            .ok_or(BridgeError::ReadResponseTimeout)
+    }
+
+    fn write(&mut self, addr: u8, data: u8) -> BridgeResult<()> {
+        let deadline = self.deadline(0);
+
+        while self.write_tx()?.is_full() {
+            if self.expired(deadline) {
+                self.write_sm.clear_fifos();
+                return Err(BridgeError::WriteTimeout);
+            }
+        }
+
+        if self.write_tx()?.write_u16_replicated(pack(addr, data)) == false {
+            return Err(BridgeError::WriteTimeout);
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> BridgeResult<()> {
+        let deadline = self.deadline(0);
+
+        while !self.write_tx()?.is_empty() {
+            if self.expired(deadline) {
+                self.write_sm.clear_fifos();
+                return Err(BridgeError::FlushTimeout);
+            }
+        }
+
+        // an empty FIFO only means the last word has been pulled; its cycle isn't over until the
+        // state machine stalls on the next pull
+        while !self.write_sm.stalled() {
+            if self.expired(deadline) {
+                return Err(BridgeError::FlushTimeout);
+            }
+        }
+
+        Ok(())
+    }
+
+    // hands the writes to DMA a buffer at a time; only the last is left in flight when this
+    // returns
+    fn write_each(
+        &mut self,
+        count: usize,
+        mut write: impl FnMut(usize) -> (u8, u8),
+    ) -> BridgeResult<()> {
+        let mut done = 0;
+
+        while done < count {
+            self.write_idle()?;
+
+            let Some((buf, _)) = self.write_dma.idle() else {
+                return Err(BridgeError::DmaBusy);
+            };
+
+            buf.clear();
+
+            while done < count && buf.remaining() > 0 {
+                let (addr, data) = write(done);
+                buf.push(pack(addr, data));
+                done += 1;
+            }
+
+            let len = buf.len();
+
+            self.write_deadline = self.deadline(len);
+            self.write_dma.start();
+        }
+
+        Ok(())
+    }
+
+    fn read_each(
+        &mut self,
+        count: usize,
+        mut addr: impl FnMut(usize) -> u8,
+        mut out: impl FnMut(u8),
+    ) -> BridgeResult<()> {
+        self.flush()?;
+
+        let mut done = 0;
+
+        while done < count {
+            let len = self.read_dma(done, count - done, &mut addr)?;
+
+            let Some((_, data)) = self.read_drain.idle() else {
+                return Err(BridgeError::DmaBusy);
+            };
+
+            for &b in data.as_slice() {
+                out(b);
+            }
+
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    // catches DMA writes that were left in flight and never finished
+    fn poll(&mut self) -> BridgeResult<()> {
+        if !self.write_dma.poll() && self.expired(self.write_deadline) {
+            self.abort_writes();
+            return Err(BridgeError::DmaTimeout);
+        }
+
+        Ok(())
+    }
+
+    // data pins are switched by the programs themselves on every cycle
+    fn attach(&mut self) {
+        self.set_pindirs(
+            self.pins.addr_mask() | self.pins.ctrl_mask(),
+            PinDir::Output,
+        );
+
+        self.read_sm.restart(self.programs.read_target());
+        self.write_sm.restart(self.programs.write_target());
+        self.read_sm.start();
+        self.write_sm.start();
+
+        self.attached = true;
+    }
+
+    // stops driving anything, so the card can be pulled or plugged in safely
+    fn release(&mut self) {
+        self.abort_writes();
+
+        self.read_sm.stop();
+        self.write_sm.stop();
+        self.read_sm.clear_fifos();
+
+        self.set_pindirs(
+            self.pins.data_mask() | self.pins.addr_mask() | self.pins.ctrl_mask(),
+            PinDir::Input,
+        );
+
+        self.attached = false;
+    }
+
+    fn set_timing(&mut self, timing: &BusTiming) -> BridgeResult<()> {
+        self.park()?;
+
+        self.programs.load(timing, ReadSM::id(), WriteSM::id());
+        self.cycle_ns = timing.cycle_ns(self.sys_hz);
+        self.read_sm.jump(self.programs.read_target());
+        self.write_sm.jump(self.programs.write_target());
+        self.read_sm
+            .clock_divisor_fixed_point(timing.clock_int, timing.clock_frac);
+        self.write_sm
+            .clock_divisor_fixed_point(timing.clock_int, timing.clock_frac);
+
+        if self.attached {
+            self.read_sm.start();
+            self.write_sm.start();
+        }
+
+        Ok(())
+    }
+
+    // drives every line in turn, so a connected card may see stray cycles
+    fn self_test(&mut self) -> BridgeResult<SelfTest> {
+        self.park()?;
+
+        let data = self.pins.data_mask();
+        let addr = self.pins.addr_mask();
+        let ctrl = self.pins.ctrl_mask();
+
+        let mut result = SelfTest {
+            tested: data | addr | ctrl,
+            ..Default::default()
+        };
+
+        self.set_pindirs(data | addr | ctrl, PinDir::Input);
+        self.delay_us(PULL_UP_SETTLE_US);
+        result.pull_up = !Sio::read_bank0() & result.tested;
+
+        // data and address with DIR pointing at the card and CLK held low
+        self.set_pins(ctrl, PinState::Low);
+        self.set_pins(1 << self.pins.dir, PinState::High);
+        self.set_pindirs(data | addr | ctrl, PinDir::Output);
+        result.walking_one |= self.walk(data | addr, PinState::High);
+        result.walking_zero |= self.walk(data | addr, PinState::Low);
+
+        // control lines with nothing driving the data lines
+        self.set_pindirs(data, PinDir::Input);
+        self.set_pins(addr, PinState::Low);
+        result.walking_one |= self.walk(ctrl, PinState::High);
+        result.walking_zero |= self.walk(ctrl, PinState::Low);
+
+        // as they were at power-up
+        self.set_pins(ctrl, PinState::Low);
+
+        if self.attached {
+            self.attach();
+        } else {
+            self.release();
+        }
+
+        Ok(result)
+    }
+}
@@ -0,0 +1,187 @@
+use card_emu_protocol::capture::{CAPTURE_LEN, CaptureConfig, CaptureState, Sampler, trigger_runs};
+use card_emu_protocol::{BridgeError, BridgeResult};
+use pio::{
+    Assembler, InSource, IrqIndexMode, JmpCondition, Label, MovDestination, MovOperation,
+    MovSource, OutDestination, Program, RP2040_MAX_PROGRAM_SIZE,
+};
+use rp235x_hal::dma::{SingleChannel, Word};
+use rp235x_hal::pac::PIO1;
+use rp235x_hal::pio::{Rx, StateMachine, Stopped, ValidStateMachine};
+
+use crate::dma::{DmaBuffer, DmaChannel};
+use crate::timing::{BusSm, install, pad};
+
+// the capture program has PIO1 to itself, so it can grow with the trigger
+pub const CAPTURE_PROGRAM_LEN: usize = RP2040_MAX_PROGRAM_SIZE;
+
+// raised once the trigger has matched
+const TRIGGER_IRQ: u8 = 0;
+
+// reading the pins for the trigger, flagging it, and the two `in`s
+const FIXED_LEN: usize = 4;
+
+type CaptureDma<CH, SM> = DmaChannel<CH, Rx<SM, Word>, DmaBuffer<u32, CAPTURE_LEN>>;
+
+type Asm = Assembler<RP2040_MAX_PROGRAM_SIZE>;
+
+// instructions needed to check that every bit in `bits` is zero
+fn check_len(bits: u32) -> usize {
+    if bits == 0 {
+        return 0;
+    }
+
+    1 + trigger_runs(bits)
+        .map(|run| usize::from(run.skip > 0) + 2)
+        .sum::<usize>()
+}
+
+// goes back to `retry` unless every bit in `bits` of X, after `op`, is zero; each run is shifted
+// out of the OSR whole and must leave Y at zero
+fn check(a: &mut Asm, retry: &mut Label, bits: u32, op: MovOperation) {
+    if bits == 0 {
+        return;
+    }
+
+    a.mov(MovDestination::OSR, op, MovSource::X);
+
+    for run in trigger_runs(bits) {
+        if run.skip > 0 {
+            a.out(OutDestination::NULL, run.skip);
+        }
+
+        a.out(OutDestination::Y, run.len);
+        a.jmp(JmpCondition::YDecNonZero, retry);
+    }
+}
+
+// waits for (pins & mask) == (value & mask), then samples every GPIO in bank 0 on each cycle; the
+// first sample is the one that matched, so the gap after it is a few cycles longer than the rest
+pub fn capture_program(
+    trigger_mask: u32,
+    trigger_value: u32,
+) -> BridgeResult<Program<RP2040_MAX_PROGRAM_SIZE>> {
+    let low = trigger_mask & !trigger_value;
+    let high = trigger_mask & trigger_value;
+
+    // two or three instructions for every run of bits that must be at the same level
+    if FIXED_LEN + check_len(low) + check_len(high) > CAPTURE_PROGRAM_LEN {
+        return Err(BridgeError::BadArgument);
+    }
+
+    let mut a = Asm::new();
+    let mut retry = a.label();
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+
+    a.bind(&mut retry);
+    a.mov(MovDestination::X, MovOperation::None, MovSource::PINS);
+    check(&mut a, &mut retry, low, MovOperation::None);
+    check(&mut a, &mut retry, high, MovOperation::Invert);
+    a.irq(false, false, TRIGGER_IRQ, IrqIndexMode::DIRECT);
+    a.r#in(InSource::X, 32);
+    a.bind(&mut wrap_target);
+    a.r#in(InSource::PINS, 32);
+    a.bind(&mut wrap_source);
+
+    Ok(pad(
+        a.assemble_with_wrap(wrap_source, wrap_target),
+        CAPTURE_PROGRAM_LEN,
+    ))
+}
+
+// the capture program's state machine, drained into a buffer by DMA
+pub struct PioSampler<SM, CH>
+where
+    SM: ValidStateMachine,
+    CH: SingleChannel,
+{
+    // only used to rewrite the program while the state machine is stopped, and for its IRQ flag
+    pio: PIO1,
+    offset: u8,
+    sm: BusSm<SM>,
+    dma: CaptureDma<CH, SM>,
+}
+
+impl<SM, CH> PioSampler<SM, CH>
+where
+    SM: ValidStateMachine,
+    CH: SingleChannel,
+{
+    pub fn new(
+        pio: PIO1,
+        offset: u8,
+        sm: StateMachine<SM, Stopped>,
+        rx: Rx<SM, Word>,
+        ch: CH,
+        buffer: &'static mut [u32; CAPTURE_LEN],
+    ) -> Self {
+        Self {
+            pio,
+            offset,
+            sm: BusSm::Stopped(sm),
+            dma: DmaChannel::new(ch, rx, DmaBuffer::new(buffer)),
+        }
+    }
+
+    fn triggered(&self) -> bool {
+        self.pio.irq().read().irq().bits() & (1 << TRIGGER_IRQ) != 0
+    }
+}
+
+impl<SM, CH> Sampler for PioSampler<SM, CH>
+where
+    SM: ValidStateMachine,
+    CH: SingleChannel,
+{
+    fn arm(&mut self, config: &CaptureConfig) -> BridgeResult<()> {
+        let program = capture_program(config.trigger_mask, config.trigger_value)?;
+
+        self.stop();
+
+        install(&self.pio, &program, self.offset, SM::id());
+        self.pio
+            .irq()
+            .write(|w| unsafe { w.irq().bits(1 << TRIGGER_IRQ) });
+
+        if let Some((_, samples)) = self.dma.idle() {
+            samples.set_len(config.samples);
+        }
+
+        self.sm
+            .clock_divisor_fixed_point(config.clock_int, config.clock_frac);
+
+        // drain first so that nothing is pushed before it's ready to be collected, then start
+        // from the trigger rather than the wrap target
+        self.dma.start();
+        self.sm.restart(self.offset);
+        self.sm.start();
+
+        Ok(())
+    }
+
+    fn stop(&mut self) {
+        self.dma.abort();
+        self.sm.stop();
+        self.sm.clear_fifos();
+    }
+
+    fn poll(&mut self) -> CaptureState {
+        if self.dma.poll() {
+            self.sm.stop();
+            return CaptureState::Done;
+        }
+
+        if self.triggered() {
+            CaptureState::Running
+        } else {
+            CaptureState::Armed
+        }
+    }
+
+    fn samples(&mut self) -> &[u32] {
+        match self.dma.idle() {
+            Some((_, samples)) => samples.as_slice(),
+            None => &[],
+        }
+    }
+}
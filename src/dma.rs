@@ -0,0 +1,144 @@
+use core::mem::replace;
+
+use rp235x_hal::dma::single_buffer::{Config, Transfer};
+use rp235x_hal::dma::{ReadTarget, SingleChannel, WriteTarget};
+
+// a statically-allocated buffer of which only the first `len` words take part in a transfer
+pub struct DmaBuffer<T: 'static, const N: usize> {
+    buf: &'static mut [T; N],
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> DmaBuffer<T, N> {
+    pub fn new(buf: &'static mut [T; N]) -> Self {
+        Self { buf, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn remaining(&self) -> usize {
+        N - self.len
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn set_len(&mut self, len: usize) {
+        self.len = len.min(N);
+    }
+
+    pub fn push(&mut self, value: T) -> bool {
+        if self.len >= N {
+            return false;
+        }
+
+        self.buf[self.len] = value;
+        self.len += 1;
+        true
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        &self.buf[..self.len]
+    }
+}
+
+unsafe impl<T, const N: usize> ReadTarget for DmaBuffer<T, N> {
+    type ReceivedWord = T;
+
+    fn rx_treq() -> Option<u8> {
+        None
+    }
+
+    fn rx_address_count(&self) -> (u32, u32) {
+        (self.buf.as_ptr() as u32, self.len as u32)
+    }
+
+    fn rx_increment(&self) -> bool {
+        true
+    }
+}
+
+unsafe impl<T, const N: usize> WriteTarget for DmaBuffer<T, N> {
+    type TransmittedWord = T;
+
+    fn tx_treq() -> Option<u8> {
+        None
+    }
+
+    fn tx_address_count(&mut self) -> (u32, u32) {
+        (self.buf.as_mut_ptr() as u32, self.len as u32)
+    }
+
+    fn tx_increment(&self) -> bool {
+        true
+    }
+}
+
+// owns a channel and both ends of its transfer, whether or not one is in flight
+pub enum DmaChannel<CH, FROM, TO>
+where
+    CH: SingleChannel,
+    FROM: ReadTarget,
+    TO: WriteTarget,
+{
+    Idle(CH, FROM, TO),
+    Busy(Transfer<CH, FROM, TO>),
+    Invalid,
+}
+
+impl<CH, FROM, TO, W> DmaChannel<CH, FROM, TO>
+where
+    CH: SingleChannel,
+    FROM: ReadTarget<ReceivedWord = W>,
+    TO: WriteTarget<TransmittedWord = W>,
+{
+    pub fn new(ch: CH, from: FROM, to: TO) -> Self {
+        Self::Idle(ch, from, to)
+    }
+
+    pub fn idle(&mut self) -> Option<(&mut FROM, &mut TO)> {
+        match self {
+            Self::Idle(_, from, to) => Some((from, to)),
+            _ => None,
+        }
+    }
+
+    pub fn start(&mut self) {
+        *self = match replace(self, Self::Invalid) {
+            Self::Idle(ch, from, to) => Self::Busy(Config::new(ch, from, to).start()),
+            s => s,
+        };
+    }
+
+    // returns true once nothing is in flight
+    pub fn poll(&mut self) -> bool {
+        if let Self::Busy(t) = self
+            && !t.is_done()
+        {
+            return false;
+        }
+
+        *self = match replace(self, Self::Invalid) {
+            Self::Busy(t) => {
+                let (ch, from, to) = t.wait();
+                Self::Idle(ch, from, to)
+            }
+            s => s,
+        };
+
+        true
+    }
+
+    pub fn abort(&mut self) {
+        *self = match replace(self, Self::Invalid) {
+            Self::Busy(t) => {
+                let (ch, from, to) = t.abort();
+                Self::Idle(ch, from, to)
+            }
+            s => s,
+        };
+    }
+}
@@ -1,28 +1,45 @@
 #![no_std]
 #![no_main]
 
+use board::PicoBoard;
 use bridge::Bridge;
+use bus::{DMA_READ_LEN, DMA_WRITE_LEN, PioBus};
+use capture::PioSampler;
+use card_emu_protocol::capture::CAPTURE_LEN;
+use card_emu_protocol::handler::Handler;
+use card_emu_protocol::info::PinMap;
+use card_emu_protocol::timing::BusTiming;
+use cortex_m::singleton;
 use panic_halt as _;
+use rom::ROM;
+use scripts::FlashScripts;
+use timing::BusPrograms;
 
-use pio::{pio_asm, pio_file};
 use rp235x_hal::binary_info::{
     EntryAddr, rp_cargo_bin_name, rp_cargo_homepage_url, rp_cargo_version,
     rp_program_build_attribute, rp_program_description,
 };
 use rp235x_hal::block::ImageDef;
 use rp235x_hal::clocks::init_clocks_and_plls;
-use rp235x_hal::dma::{Byte, HalfWord};
+use rp235x_hal::dma::{Byte, DMAExt, HalfWord, Word};
 use rp235x_hal::gpio::{DynPinId, FunctionPio0, Pin, PinGroup, PinState, Pins, PullUp};
-use rp235x_hal::pac::Peripherals;
-use rp235x_hal::pio::{PIOBuilder, PIOExt, PinDir, ShiftDirection};
+use rp235x_hal::pac::{PIO0, PIO1, Peripherals};
+use rp235x_hal::pio::{Buffers, PIOBuilder, PIOExt, PinDir, ShiftDirection};
 use rp235x_hal::usb::UsbBus;
-use rp235x_hal::{Sio, Timer, Watchdog};
+use rp235x_hal::{Clock, Sio, Timer, Watchdog};
 use usb_device::LangID;
 use usb_device::bus::UsbBusAllocator;
 use usb_device::device::{StringDescriptors, UsbDeviceBuilder, UsbVidPid};
 
+mod board;
 mod bridge;
+mod bus;
+mod capture;
+mod dma;
 mod rom;
+mod scripts;
+mod selftest;
+mod timing;
 
 #[unsafe(link_section = ".start_block")]
 #[used]
@@ -57,7 +74,7 @@ fn main() -> ! {
     )
     .unwrap();
 
-    //let timer = Timer::new_timer0(pac.TIMER0, &mut pac.RESETS, &clocks);
+    let timer = Timer::new_timer0(pac.TIMER0, &mut pac.RESETS, &clocks);
 
     let sio = Sio::new(pac.SIO);
 
@@ -95,48 +112,25 @@ fn main() -> ! {
         pins.gpio17.into_function().into_pull_type().into_dyn_pin(),
     ];
 
+    // the card loops detect back to sense, so sense only reads high while one is connected
     let detect = pins.gpio18.into_push_pull_output_in_state(PinState::High);
+    let sense = pins.gpio19.into_pull_down_input().into_dyn_pin();
 
     let (mut pio0, sm0, sm1, _, _) = pac.PIO0.split(&mut pac.RESETS);
 
-    // side-set 0 is DIR
-    // side-set 1 is CLK
-    let read = pio_asm!(
-        "
-        .side_set 2 opt
-        ; .in 8 left auto 8
-        ; .out 16 right auto 16
-        ; .clock_div 20
-
-            pull block
-            out x, 32
-        .wrap_target
-            pull block
-            out  pins,    16    side 0b00
-            mov  pindirs, x     [1]
-            in   pins,    8
-        .wrap
-        "
+    let read_installed = pio0
+        .install(&timing::read_program(&BusTiming::DEFAULT))
+        .unwrap();
+    let write_installed = pio0
+        .install(&timing::write_program(&BusTiming::DEFAULT))
+        .unwrap();
+
+    // only used to reassemble these two programs in place while their state machines are stopped
+    let programs = BusPrograms::new(
+        unsafe { PIO0::steal() },
+        read_installed.offset(),
+        write_installed.offset(),
     );
-    let write = pio_asm!(
-        "
-        .side_set 2 opt
-        ; .out 16 right auto 16
-        ; .clock_div 20
-
-            pull block
-            out x, 32
-        .wrap_target
-            pull block
-            out  pins,    16    side 0b01
-            mov  pindirs, x
-            nop                 side 0b11
-            nop                 side 0b01
-        .wrap
-        "
-    );
-    let read_installed = pio0.install(&read.program).unwrap();
-    let write_installed = pio0.install(&write.program).unwrap();
 
     let (mut read_sm, read_rx, mut read_tx) = PIOBuilder::from_installed_program(read_installed)
         .out_pins(data[0].id().num, (data.len() + addr.len()) as _)
@@ -149,7 +143,7 @@ fn main() -> ! {
         .pull_threshold(16)*/
         .autopush(true)
         .push_threshold(8)
-        .clock_divisor_fixed_point(7, 0)
+        .clock_divisor_fixed_point(BusTiming::DEFAULT.clock_int, BusTiming::DEFAULT.clock_frac)
         .build(sm0);
 
     let (write_sm, _, mut write_tx) = PIOBuilder::from_installed_program(write_installed)
@@ -157,9 +151,29 @@ fn main() -> ! {
         .out_shift_direction(ShiftDirection::Right)
         .side_set_pin_base(ctrl[0].id().num)
         .autopull(false)
-        .clock_divisor_fixed_point(7, 0)
+        .clock_divisor_fixed_point(BusTiming::DEFAULT.clock_int, BusTiming::DEFAULT.clock_frac)
         .build(sm1);
 
+    // the capture program is rewritten for every trigger, so it's given PIO1 to itself; this
+    // untriggered one only holds its place
+    let (mut pio1, capture_sm, _, _, _) = pac.PIO1.split(&mut pac.RESETS);
+
+    let capture_installed = pio1
+        .install(&capture::capture_program(0, 0).unwrap())
+        .unwrap();
+    let capture_offset = capture_installed.offset();
+
+    // samples every pin in bank 0, and is left stopped until a capture is started; the trigger
+    // shifts the pins out LSB first
+    let (capture_sm, capture_rx, _) = PIOBuilder::from_installed_program(capture_installed)
+        .in_pin_base(0)
+        .in_shift_direction(ShiftDirection::Left)
+        .out_shift_direction(ShiftDirection::Right)
+        .autopush(true)
+        .push_threshold(32)
+        .buffers(Buffers::OnlyRx)
+        .build(capture_sm);
+
     read_sm.set_pindirs(ctrl.iter().map(|p| (p.id().num, PinDir::Output)));
     read_sm.set_pindirs(addr.iter().map(|p| (p.id().num, PinDir::Output)));
 
@@ -169,6 +183,24 @@ fn main() -> ! {
     read_tx.write(0b11111111_11111111_11111111_00000000);
     write_tx.write(0b11111111_11111111_11111111_11111111);
 
+    let pin_map = PinMap {
+        data_base: data[0].id().num,
+        data_len: data.len() as _,
+        addr_base: addr[0].id().num,
+        addr_len: addr.len() as _,
+        dir: ctrl[0].id().num,
+        clk: ctrl[1].id().num,
+        detect: detect.id().num,
+        sense: sense.id().num,
+    };
+
+    let dma = pac.DMA.split(&mut pac.RESETS);
+
+    let write_buffer = singleton!(: [u16; DMA_WRITE_LEN] = [0; DMA_WRITE_LEN]).unwrap();
+    let feed_buffer = singleton!(: [u16; DMA_READ_LEN] = [0; DMA_READ_LEN]).unwrap();
+    let drain_buffer = singleton!(: [u8; DMA_READ_LEN] = [0; DMA_READ_LEN]).unwrap();
+    let capture_buffer = singleton!(: [u32; CAPTURE_LEN] = [0; CAPTURE_LEN]).unwrap();
+
     let usb_bus = UsbBusAllocator::new(UsbBus::new(
         pac.USB,
         pac.USB_DPRAM,
@@ -177,21 +209,55 @@ fn main() -> ! {
         &mut pac.RESETS,
     ));
 
-    let mut driver = Bridge::new(
-        &usb_bus,
+    let bus = PioBus::new(
+        timer,
+        clocks.system_clock.freq().to_Hz(),
+        pin_map,
         (
             read_sm,
             read_rx.transfer_size(Byte),
-            read_tx.transfer_size(Byte),
+            read_tx.transfer_size(HalfWord),
         ),
         (write_sm, write_tx.transfer_size(HalfWord)),
+        programs,
+        (dma.ch0, dma.ch1, dma.ch2),
+        (write_buffer, feed_buffer, drain_buffer),
     );
 
+    let sampler = PioSampler::new(
+        unsafe { PIO1::steal() },
+        capture_offset,
+        capture_sm,
+        capture_rx.transfer_size(Word),
+        dma.ch3,
+        capture_buffer,
+    );
+
+    let handler = Handler::new(
+        bus,
+        PicoBoard::new(timer, sense),
+        sampler,
+        FlashScripts,
+        pin_map,
+        firmware_version(),
+    );
+
+    let mut driver = Bridge::new(&usb_bus, handler);
+
+    driver.run_boot_script();
+
+    // so that more than one bridge can be told apart
+    let mut serial = [0; 16];
+    let serial = match unsafe { ROM::chip_id() } {
+        Some(id) => hex_serial(id, &mut serial),
+        None => "PARTNER-N64",
+    };
+
     let mut usb_dev = UsbDeviceBuilder::new(&usb_bus, UsbVidPid(0x0ED2, 0x64DD))
         .strings(&[StringDescriptors::new(LangID::EN_GB)
             .manufacturer("Kyoto Micro Computer Co., Ltd")
             .product("Partner-N64 USB interface")
-            .serial_number("PARTNER-N64")])
+            .serial_number(serial)])
         .unwrap()
         .max_packet_size_0(64)
         .unwrap()
@@ -199,28 +265,36 @@ fn main() -> ! {
         .build();
 
     loop {
-        if usb_dev.poll(&mut [&mut driver]) {
-            match driver.read() {
-                Err(_) => {
-                    // do nothing
-                }
-                Ok(n) => {
-                    driver.receive(n).unwrap();
-                }
-            }
-
-            match driver.write() {
-                Err(_) => {
-                    // do nothing
-                }
-                Ok(n) => {
-                    driver.clear(n).unwrap();
-                }
-            }
-        }
+        driver.update();
+
+        usb_dev.poll(&mut [&mut driver]);
+
+        // not only after an endpoint event: a packet left unread while the buffer was full,
+        // or data queued once the IN endpoint went idle, would otherwise never be picked up;
+        // either is WouldBlock whenever there's nothing to do
+        let _ = driver.read();
+        let _ = driver.write();
     }
 }
 
+fn firmware_version() -> [u8; 3] {
+    [
+        env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+        env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+        env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+    ]
+}
+
+// upper-case, most significant digit first
+fn hex_serial(id: u64, buf: &mut [u8; 16]) -> &str {
+    for (i, digit) in buf.iter_mut().enumerate() {
+        let nibble = (id >> (60 - 4 * i)) & 0xF;
+        *digit = b"0123456789ABCDEF"[nibble as usize];
+    }
+
+    core::str::from_utf8(buf).unwrap()
+}
+
 #[unsafe(link_section = ".bi_entries")]
 #[used]
 pub static PICOTOOL_ENTRIES: [EntryAddr; 5] = [
@@ -5,6 +5,22 @@ const BOOTROM_VERSION_OFFSET: u16 = 0x12;
 
 const BOOTROM_FUNC_TABLE_OFFSET: u16 = 0x14;
 
+pub const FLASH_SECTOR_SIZE: u32 = 1 << 12;
+pub const FLASH_PAGE_SIZE: usize = 1 << 8;
+
+const FLASH_BLOCK_SIZE: u32 = 1 << 16;
+const FLASH_BLOCK_ERASE_CMD: u8 = 0xD8;
+
+// where the code that puts the flash back into XIP mode can be copied from: boot2 on the
+// RP2040, and the bootrom's own copy in boot RAM on the RP235x
+const RP2040_BOOT2_ADDR: usize = 0x1000_0000;
+const RP235X_XIP_SETUP_ADDR: usize = 0x400e_0000;
+const XIP_SETUP_SIZE: usize = 256;
+
+// XIP is unavailable while the flash is being written, so this has to be in RAM
+static mut XIP_SETUP: [u32; XIP_SETUP_SIZE / size_of::<u32>()] =
+    [0; XIP_SETUP_SIZE / size_of::<u32>()];
+
 pub struct ROM;
 
 enum BootromVersion {
@@ -12,6 +28,21 @@ enum BootromVersion {
     RP235x,
 }
 
+type VoidFn = unsafe extern "C" fn();
+type FlashRangeEraseFn =
+    unsafe extern "C" fn(addr: u32, count: usize, block_size: u32, block_cmd: u8);
+type FlashRangeProgramFn = unsafe extern "C" fn(addr: u32, data: *const u8, count: usize);
+
+// everything has to be looked up before XIP goes away
+struct FlashFuncs {
+    connect_internal_flash: VoidFn,
+    flash_exit_xip: VoidFn,
+    flash_range_erase: FlashRangeEraseFn,
+    flash_range_program: FlashRangeProgramFn,
+    flash_flush_cache: VoidFn,
+    xip_setup: VoidFn,
+}
+
 impl ROM {
     pub unsafe fn reset_usb_boot(
         activity_gpio: Option<u8>,
@@ -106,6 +137,114 @@ impl ROM {
         unreachable!("reboot failed, error code {ret}");
     }
 
+    // unique to each chip; only the RP235x bootrom has one to give
+    pub unsafe fn chip_id() -> Option<u64> {
+        match unsafe { Self::check_bootrom_magic() } {
+            Some(BootromVersion::RP235x) => unsafe { Self::chip_id_rp235x() },
+            _ => None,
+        }
+    }
+
+    unsafe fn chip_id_rp235x() -> Option<u64> {
+        let func_ptr = unsafe { Self::rp235x_rom_func_lookup(Self::rom_table_code(b"GS")) };
+
+        if func_ptr.is_null() {
+            return None;
+        }
+
+        type RomGetSysInfoFn =
+            unsafe extern "C" fn(out: *mut u32, out_words: u32, flags: u32) -> core::ffi::c_int;
+
+        const SYS_INFO_CHIP_INFO: u32 = 0x0001;
+
+        let func = unsafe { core::mem::transmute::<*const (), RomGetSysInfoFn>(func_ptr) };
+
+        // the flags that were answered, then the package, device ID and wafer ID
+        let mut out = [0u32; 4];
+
+        let ret = unsafe { func(out.as_mut_ptr(), out.len() as _, SYS_INFO_CHIP_INFO) };
+
+        if ret < out.len() as _ || out[0] & SYS_INFO_CHIP_INFO == 0 {
+            return None;
+        }
+
+        Some(u64::from(out[2]) << 32 | u64::from(out[3]))
+    }
+
+    // `offset` and `count` must be multiples of FLASH_SECTOR_SIZE, and nothing may be executing
+    // from or reading the flash in the meantime
+    pub unsafe fn flash_range_erase(offset: u32, count: u32) {
+        let funcs = unsafe { Self::flash_funcs() };
+
+        cortex_m::interrupt::free(|_| unsafe { flash_range_erase_ram(&funcs, offset, count) });
+    }
+
+    // `offset` and `data.len()` must be multiples of FLASH_PAGE_SIZE, the range must have been
+    // erased, and `data` must not itself be in flash
+    pub unsafe fn flash_range_program(offset: u32, data: &[u8]) {
+        let funcs = unsafe { Self::flash_funcs() };
+
+        cortex_m::interrupt::free(|_| unsafe {
+            flash_range_program_ram(&funcs, offset, data.as_ptr(), data.len())
+        });
+    }
+
+    unsafe fn flash_funcs() -> FlashFuncs {
+        let version = unsafe { Self::check_bootrom_magic() };
+
+        let lookup = |ident: &[u8; 2]| -> *const () {
+            let code = Self::rom_table_code(ident);
+
+            let func = match version {
+                Some(BootromVersion::RP2040) => unsafe { Self::rp2040_rom_func_lookup(code) },
+                Some(BootromVersion::RP235x) => unsafe { Self::rp235x_rom_func_lookup(code) },
+                None => panic!("unknown bootrom version"),
+            };
+
+            if func.is_null() {
+                panic!(
+                    "missing bootrom function {}{}",
+                    ident[0] as char, ident[1] as char
+                );
+            }
+
+            func
+        };
+
+        let xip_setup_src = match version {
+            Some(BootromVersion::RP2040) => RP2040_BOOT2_ADDR,
+            _ => RP235X_XIP_SETUP_ADDR,
+        };
+
+        let xip_setup = &raw mut XIP_SETUP;
+
+        unsafe {
+            for i in 0..(*xip_setup).len() {
+                (*xip_setup)[i] =
+                    core::ptr::with_exposed_provenance::<u32>(xip_setup_src + i * size_of::<u32>())
+                        .read_volatile();
+            }
+        }
+
+        // thumb bit
+        let xip_setup = (xip_setup as usize | 1) as *const ();
+
+        unsafe {
+            FlashFuncs {
+                connect_internal_flash: core::mem::transmute::<*const (), VoidFn>(lookup(b"IF")),
+                flash_exit_xip: core::mem::transmute::<*const (), VoidFn>(lookup(b"EX")),
+                flash_range_erase: core::mem::transmute::<*const (), FlashRangeEraseFn>(lookup(
+                    b"RE",
+                )),
+                flash_range_program: core::mem::transmute::<*const (), FlashRangeProgramFn>(
+                    lookup(b"RP"),
+                ),
+                flash_flush_cache: core::mem::transmute::<*const (), VoidFn>(lookup(b"FC")),
+                xip_setup: core::mem::transmute::<*const (), VoidFn>(xip_setup),
+            }
+        }
+    }
+
     unsafe fn rom_read<T>(rom_address: u16) -> T {
         unsafe { core::ptr::with_exposed_provenance::<T>(rom_address.into()).read_volatile() }
     }
@@ -173,3 +312,32 @@ impl ROM {
         }
     }
 }
+
+#[inline(never)]
+#[unsafe(link_section = ".data.ram_func")]
+unsafe fn flash_range_erase_ram(funcs: &FlashFuncs, offset: u32, count: u32) {
+    unsafe {
+        (funcs.connect_internal_flash)();
+        (funcs.flash_exit_xip)();
+        (funcs.flash_range_erase)(
+            offset,
+            count as usize,
+            FLASH_BLOCK_SIZE,
+            FLASH_BLOCK_ERASE_CMD,
+        );
+        (funcs.flash_flush_cache)();
+        (funcs.xip_setup)();
+    }
+}
+
+#[inline(never)]
+#[unsafe(link_section = ".data.ram_func")]
+unsafe fn flash_range_program_ram(funcs: &FlashFuncs, offset: u32, data: *const u8, len: usize) {
+    unsafe {
+        (funcs.connect_internal_flash)();
+        (funcs.flash_exit_xip)();
+        (funcs.flash_range_program)(offset, data, len);
+        (funcs.flash_flush_cache)();
+        (funcs.xip_setup)();
+    }
+}
@@ -0,0 +1,80 @@
+use card_emu_protocol::program::{
+    PROGRAM_SIZE, SCRIPT_HEADER_LEN, SCRIPT_SLOTS, ScriptHeader, ScriptStore,
+};
+use card_emu_protocol::{BridgeError, BridgeResult};
+
+use crate::rom::{FLASH_PAGE_SIZE, FLASH_SECTOR_SIZE, ROM};
+
+// must match SCRIPTS in memory.x
+const SCRIPT_FLASH_OFFSET: u32 = 0x1F_0000;
+const SCRIPT_FLASH_SIZE: u32 = 64 * 1024;
+
+// flash is readable here while XIP is on
+const XIP_BASE: usize = 0x1000_0000;
+
+// one sector per script, so each can be erased on its own
+const _: () = assert!(SCRIPT_SLOTS == (SCRIPT_FLASH_SIZE / FLASH_SECTOR_SIZE) as usize);
+
+// header and program, rounded up to whole pages
+const SCRIPT_IMAGE_LEN: usize =
+    (SCRIPT_HEADER_LEN + PROGRAM_SIZE).next_multiple_of(FLASH_PAGE_SIZE);
+
+fn slot_offset(slot: usize) -> BridgeResult<u32> {
+    if slot >= SCRIPT_SLOTS {
+        return Err(BridgeError::BadArgument);
+    }
+
+    Ok(SCRIPT_FLASH_OFFSET + slot as u32 * FLASH_SECTOR_SIZE)
+}
+
+fn flash(offset: u32, len: usize) -> &'static [u8] {
+    let ptr = core::ptr::with_exposed_provenance::<u8>(XIP_BASE + offset as usize);
+    unsafe { core::slice::from_raw_parts(ptr, len) }
+}
+
+// scripts in the flash set aside for them
+pub struct FlashScripts;
+
+impl ScriptStore for FlashScripts {
+    fn header(&self, slot: usize) -> BridgeResult<ScriptHeader> {
+        let offset = slot_offset(slot)?;
+
+        ScriptHeader::decode(flash(offset, SCRIPT_HEADER_LEN))
+    }
+
+    fn load(&self, slot: usize, out: &mut [u8; PROGRAM_SIZE]) -> BridgeResult<usize> {
+        let header = self.header(slot)?;
+        let len = usize::from(header.len);
+
+        let offset = slot_offset(slot)? + SCRIPT_HEADER_LEN as u32;
+        out[..len].copy_from_slice(flash(offset, len));
+
+        Ok(len)
+    }
+
+    fn store(&mut self, slot: usize, header: &ScriptHeader, program: &[u8]) -> BridgeResult<()> {
+        let offset = slot_offset(slot)?;
+
+        // unprogrammed bytes are left erased
+        let mut image = [0xFF; SCRIPT_IMAGE_LEN];
+        image[..SCRIPT_HEADER_LEN].copy_from_slice(&header.encode());
+        image[SCRIPT_HEADER_LEN..SCRIPT_HEADER_LEN + program.len()].copy_from_slice(program);
+
+        let len = (SCRIPT_HEADER_LEN + program.len()).next_multiple_of(FLASH_PAGE_SIZE);
+
+        unsafe {
+            ROM::flash_range_erase(offset, FLASH_SECTOR_SIZE);
+            ROM::flash_range_program(offset, &image[..len]);
+        }
+
+        Ok(())
+    }
+
+    fn erase(&mut self, slot: usize) -> BridgeResult<()> {
+        let offset = slot_offset(slot)?;
+
+        unsafe { ROM::flash_range_erase(offset, FLASH_SECTOR_SIZE) };
+
+        Ok(())
+    }
+}
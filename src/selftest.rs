@@ -0,0 +1,4 @@
+// the GPIOs set in a mask, lowest first
+pub fn pins_in(mask: u32) -> impl Iterator<Item = u8> {
+    (0..u32::BITS as u8).filter(move |&pin| mask & (1 << pin) != 0)
+}
@@ -0,0 +1,291 @@
+use core::mem::replace;
+
+use card_emu_protocol::timing::{BusTiming, MAX_PHASE_CYCLES};
+use pio::{
+    Assembler, InSource, Instruction, InstructionOperands, JmpCondition, MovDestination,
+    MovOperation, MovSource, OutDestination, Program, RP2040_MAX_PROGRAM_SIZE, SetDestination,
+    SideSet,
+};
+use rp235x_hal::pac::{PIO0, pio0};
+use rp235x_hal::pio::{PinDir, PinState, Running, StateMachine, Stopped, ValidStateMachine};
+
+// side-set 0 is DIR
+// side-set 1 is CLK
+const SIDE_SET: SideSet = SideSet::new(true, 2, false);
+
+// two side-set bits plus the enable bit leave two bits of delay
+const MAX_DELAY: u8 = 3;
+
+// an instruction with its full delay, then `set y` with its full delay and 32 passes round a
+// `jmp y--` with its full delay
+const _: () = assert!(MAX_PHASE_CYCLES == 1 + MAX_DELAY + 1 + MAX_DELAY + 32 * (1 + MAX_DELAY));
+
+// both programs start with a pull and an out that load the pindirs mask into X, then wrap back
+// to here for every cycle, whatever the timing
+const WRAP_TARGET: u8 = 2;
+
+// the longest each program can get, with every phase needing a countdown loop; they're always
+// padded out to this so they can be rewritten in place
+const READ_PROGRAM_LEN: usize = 6 + 3 * 2;
+const WRITE_PROGRAM_LEN: usize = 7 + 3 * 2;
+
+// `mov y, y`, for the padding
+const NOP: u16 = InstructionOperands::MOV {
+    destination: MovDestination::Y,
+    op: MovOperation::None,
+    source: MovSource::Y,
+}
+.encode();
+
+type Asm = Assembler<RP2040_MAX_PROGRAM_SIZE>;
+
+// emits `anchor` with as much of the phase as fits in its delay, then makes up the rest
+fn phase(a: &mut Asm, cycles: u8, anchor: impl FnOnce(&mut Asm, u8)) {
+    let extra = cycles - 1;
+    let delay = extra.min(MAX_DELAY);
+    anchor(a, delay);
+
+    let rest = extra - delay;
+
+    if rest == 0 {
+        return;
+    }
+
+    if rest <= 1 + MAX_DELAY {
+        a.nop_with_delay(rest - 1);
+        return;
+    }
+
+    // the set takes 1 + remainder, and each pass round the loop 1 + MAX_DELAY
+    let passes = (rest - 1) / (1 + MAX_DELAY);
+    let remainder = (rest - 1) % (1 + MAX_DELAY);
+
+    let mut countdown = a.label();
+    a.set_with_delay(SetDestination::Y, passes - 1, remainder);
+    a.bind(&mut countdown);
+    a.jmp_with_delay(JmpCondition::YDecNonZero, &mut countdown, MAX_DELAY);
+}
+
+pub fn pad(
+    mut program: Program<RP2040_MAX_PROGRAM_SIZE>,
+    len: usize,
+) -> Program<RP2040_MAX_PROGRAM_SIZE> {
+    // past the wrap, so never executed
+    while program.code.len() < len {
+        program.code.push(NOP);
+    }
+
+    program
+}
+
+// the first word written is the pindirs mask, then each one is address|data
+pub fn read_program(timing: &BusTiming) -> Program<RP2040_MAX_PROGRAM_SIZE> {
+    let mut a = Asm::new_with_side_set(SIDE_SET);
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+
+    a.pull(false, true);
+    a.out(OutDestination::X, 32);
+    a.bind(&mut wrap_target);
+    a.pull(false, true);
+    phase(&mut a, timing.setup, |a, d| {
+        a.out_with_delay_and_side_set(OutDestination::PINS, 16, d, 0b00)
+    });
+    phase(&mut a, timing.sample, |a, d| {
+        a.mov_with_delay(MovDestination::PINDIRS, MovOperation::None, MovSource::X, d)
+    });
+    phase(&mut a, timing.hold, |a, d| {
+        a.in_with_delay(InSource::PINS, 8, d)
+    });
+    a.bind(&mut wrap_source);
+
+    pad(
+        a.assemble_with_wrap(wrap_source, wrap_target),
+        READ_PROGRAM_LEN,
+    )
+}
+
+pub fn write_program(timing: &BusTiming) -> Program<RP2040_MAX_PROGRAM_SIZE> {
+    let mut a = Asm::new_with_side_set(SIDE_SET);
+    let mut wrap_target = a.label();
+    let mut wrap_source = a.label();
+
+    a.pull(false, true);
+    a.out(OutDestination::X, 32);
+    a.bind(&mut wrap_target);
+    a.pull(false, true);
+    a.out_with_side_set(OutDestination::PINS, 16, 0b01);
+    phase(&mut a, timing.setup, |a, d| {
+        a.mov_with_delay(MovDestination::PINDIRS, MovOperation::None, MovSource::X, d)
+    });
+    phase(&mut a, timing.strobe, |a, d| {
+        a.nop_with_delay_and_side_set(d, 0b11)
+    });
+    phase(&mut a, timing.hold, |a, d| {
+        a.nop_with_delay_and_side_set(d, 0b01)
+    });
+    a.bind(&mut wrap_source);
+
+    pad(
+        a.assemble_with_wrap(wrap_source, wrap_target),
+        WRITE_PROGRAM_LEN,
+    )
+}
+
+// where the programs were installed, so they can be reassembled in place
+pub struct BusPrograms {
+    pio: PIO0,
+    read_offset: u8,
+    write_offset: u8,
+}
+
+impl BusPrograms {
+    pub fn new(pio: PIO0, read_offset: u8, write_offset: u8) -> Self {
+        Self {
+            pio,
+            read_offset,
+            write_offset,
+        }
+    }
+
+    // where to send each state machine to begin a fresh cycle
+    pub fn read_target(&self) -> u8 {
+        self.read_offset + WRAP_TARGET
+    }
+
+    pub fn write_target(&self) -> u8 {
+        self.write_offset + WRAP_TARGET
+    }
+
+    // both state machines must be stopped, and then sent to their targets, as the instruction
+    // either was stopped on may have changed under it
+    pub fn load(&mut self, timing: &BusTiming, read_sm: usize, write_sm: usize) {
+        self.install(&read_program(timing), self.read_offset, read_sm);
+        self.install(&write_program(timing), self.write_offset, write_sm);
+    }
+
+    fn install(&self, program: &Program<RP2040_MAX_PROGRAM_SIZE>, offset: u8, sm: usize) {
+        debug_assert_eq!(program.wrap.target, WRAP_TARGET);
+        install(&self.pio, program, offset, sm);
+    }
+}
+
+// writes `program` over whatever was at `offset`, which `sm` mustn't be running
+pub fn install(
+    pio: &pio0::RegisterBlock,
+    program: &Program<RP2040_MAX_PROGRAM_SIZE>,
+    offset: u8,
+    sm: usize,
+) {
+    for (i, &instr) in program.code.iter().enumerate() {
+        // relocate jumps, as PIO::install does
+        let instr = if instr & 0b1110_0000_0000_0000 == 0 {
+            (instr & !0b11111) | u16::from((instr & 0b11111) as u8 + offset)
+        } else {
+            instr
+        };
+
+        pio.instr_mem(usize::from(offset) + i)
+            .write(|w| unsafe { w.instr_mem0().bits(instr) });
+    }
+
+    pio.sm(sm).sm_execctrl().modify(|_, w| unsafe {
+        w.wrap_top()
+            .bits(offset + program.wrap.source)
+            .wrap_bottom()
+            .bits(offset + program.wrap.target)
+    });
+}
+
+// a state machine that can be stopped and started again without giving it up
+pub enum BusSm<SM: ValidStateMachine> {
+    Running(StateMachine<SM, Running>),
+    Stopped(StateMachine<SM, Stopped>),
+    Invalid,
+}
+
+impl<SM: ValidStateMachine> BusSm<SM> {
+    pub fn new(sm: StateMachine<SM, Running>) -> Self {
+        Self::Running(sm)
+    }
+
+    pub fn stop(&mut self) {
+        *self = match replace(self, Self::Invalid) {
+            Self::Running(sm) => Self::Stopped(sm.stop()),
+            s => s,
+        };
+    }
+
+    pub fn start(&mut self) {
+        *self = match replace(self, Self::Invalid) {
+            Self::Stopped(sm) => Self::Running(sm.start()),
+            s => s,
+        };
+    }
+
+    // executes a jump straight away, whether running or not
+    pub fn jump(&mut self, target: u8) {
+        let jmp = Instruction {
+            operands: InstructionOperands::JMP {
+                condition: JmpCondition::Always,
+                address: target,
+            },
+            delay: 0,
+            side_set: None,
+        };
+
+        match self {
+            Self::Running(sm) => sm.exec_instruction(jmp),
+            Self::Stopped(sm) => sm.exec_instruction(jmp),
+            Self::Invalid => {}
+        }
+    }
+
+    // SM_RESTART only clears the ISR, the shift counters and any stall, leaving the PC, the OSR
+    // and X/Y alone, so it's also sent to `target` by hand in case it was stopped mid-cycle; only
+    // while stopped, so nothing stale runs in between
+    pub fn restart(&mut self, target: u8) {
+        if let Self::Stopped(sm) = self {
+            sm.restart();
+            self.jump(target);
+        }
+    }
+
+    // only while stopped
+    pub fn set_pindirs(&mut self, pindirs: impl IntoIterator<Item = (u8, PinDir)>) {
+        if let Self::Stopped(sm) = self {
+            sm.set_pindirs(pindirs);
+        }
+    }
+
+    // only while stopped
+    pub fn set_pins(&mut self, pins: impl IntoIterator<Item = (u8, PinState)>) {
+        if let Self::Stopped(sm) = self {
+            sm.set_pins(pins);
+        }
+    }
+
+    pub fn stalled(&self) -> bool {
+        match self {
+            Self::Running(sm) => sm.stalled(),
+            Self::Stopped(sm) => sm.stalled(),
+            Self::Invalid => true,
+        }
+    }
+
+    pub fn clear_fifos(&mut self) {
+        match self {
+            Self::Running(sm) => sm.clear_fifos(),
+            Self::Stopped(sm) => sm.clear_fifos(),
+            Self::Invalid => {}
+        }
+    }
+
+    pub fn clock_divisor_fixed_point(&mut self, int: u16, frac: u8) {
+        match self {
+            Self::Running(sm) => sm.clock_divisor_fixed_point(int, frac),
+            Self::Stopped(sm) => sm.clock_divisor_fixed_point(int, frac),
+            Self::Invalid => {}
+        }
+    }
+}